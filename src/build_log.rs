@@ -0,0 +1,243 @@
+// Every build appends a record per job to beaver_log.jsonl in the build directory, and a record
+// for the build itself. `beaver stats` summarizes the log, nothing is sent anywhere.
+// {"type":"job","run":1700000000000,"target":"vec_lib","source":"vec.cpp","duration":1.5,...}
+// {"type":"build","run":1700000000000,"start":1700000000.0,"end":1700000004.2,"succeeded":true}
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use crate::build_observer::{BuildObserver, Job};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::work_pool::{WorkResult, hash_command_line};
+
+const NUMBER_OF_ENTRIES: usize = 10;
+// Beyond this size the log is moved aside when a build starts, replacing the log moved aside before.
+const MAX_LOG_SIZE: u64 = 64 * 1024 * 1024;
+
+pub fn get_build_log_file(build_dir: &str) -> String {
+    return format!("{}/beaver_log.jsonl", build_dir);
+}
+
+fn get_old_build_log_file(build_dir: &str) -> String {
+    return format!("{}/beaver_log.jsonl.old", build_dir);
+}
+
+/// Appends the records of the build to the log.
+pub struct BuildLogObserver {
+    build_dir: String,
+    file: Option<File>,
+    // Identifies the records of a build, the milliseconds since the epoch when it started.
+    run: u128,
+    start: f64,
+    number_of_jobs: usize,
+}
+
+impl BuildLogObserver {
+    pub fn new(build_dir: &str) -> BuildLogObserver {
+        return BuildLogObserver {
+            build_dir: build_dir.to_owned(),
+            file: None,
+            run: 0,
+            start: 0.0,
+            number_of_jobs: 0,
+        };
+    }
+
+    fn write(&mut self, record: Value) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.write_all(format!("{}\n", record).as_bytes()) {
+            println!("ERROR: Could not write the build log: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+impl BuildObserver for BuildLogObserver {
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {
+        let log_file = get_build_log_file(&self.build_dir);
+        if fs::metadata(&log_file).is_ok_and(|metadata| metadata.len() > MAX_LOG_SIZE) {
+            let old_log_file = get_old_build_log_file(&self.build_dir);
+            if let Err(e) = fs::rename(&log_file, &old_log_file) {
+                println!("ERROR: Could not move {} to {}: {}", log_file, old_log_file, e);
+            }
+        }
+        self.file = match OpenOptions::new().create(true).append(true).open(&log_file) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                println!("ERROR: Could not open {}: {}", log_file, e);
+                None
+            },
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.run = now.as_millis();
+        self.start = now.as_secs_f64();
+        self.number_of_jobs = 0;
+    }
+
+    fn job_finished(&mut self, graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        let end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let (output, succeeded) = match &result.result {
+            Ok(output) => (output, true),
+            Err(output) => (output, false),
+        };
+        self.number_of_jobs += 1;
+        self.write(json!({
+            "type": "job",
+            "run": self.run,
+            "target": graph.get_name(job.target),
+            "description": job.description,
+            "source": job.source_file,
+            "output_file": job.output_file,
+            "command_hash": format!("{:016x}", hash_command_line(&job.command_line)),
+            "start": end - result.duration.as_secs_f64(),
+            "end": end,
+            "duration": result.duration.as_secs_f64(),
+            "succeeded": succeeded,
+            "exit_status": result.process_status.map(|process_status| process_status.exit_status),
+            "peak_rss": result.process_status.map(|process_status| process_status.peak_rss),
+            // The warnings of successful jobs are in diagnostics.json, only failures are kept.
+            "output": if succeeded { None } else { Some(output) },
+        }));
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, succeeded: bool, _number_of_unbuilt_targets: usize) {
+        let end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        self.write(json!({
+            "type": "build",
+            "run": self.run,
+            "start": self.start,
+            "end": end,
+            "succeeded": succeeded,
+            "number_of_jobs": self.number_of_jobs,
+        }));
+        self.file = None;
+    }
+}
+
+/// Returns the records of the log moved aside, if any, followed by those of the log.
+fn read_records(build_dir: &str) -> Result<Vec<Value>, String> {
+    let log_file = get_build_log_file(build_dir);
+    let content = fs::read_to_string(&log_file).map_err(|e| format!("Could not read {}: {}", log_file, e))?;
+    let old_content = fs::read_to_string(get_old_build_log_file(build_dir)).unwrap_or_default();
+    // A line can be cut off when a build was interrupted.
+    return Ok(old_content.lines().chain(content.lines()).filter_map(|line| serde_json::from_str(line).ok()).collect());
+}
+
+/// Returns the record of the last successful job of each output file in the log.
+fn read_last_jobs(build_dir: &str) -> Result<HashMap<String, Value>, String> {
+    let mut jobs = HashMap::new();
+    for record in read_records(build_dir)? {
+        if record["type"] != "job" || record["succeeded"] != true {
+            continue;
+        }
+        if let Some(output_file) = record["output_file"].as_str() {
+            jobs.insert(output_file.to_owned(), record);
+        }
+    }
+
+    return Ok(jobs);
+}
+
+/// Returns how long the last successful job of each output file took.
+pub fn read_job_durations(build_dir: &str) -> Result<HashMap<String, f64>, String> {
+    let jobs = read_last_jobs(build_dir)?;
+    return Ok(jobs.into_iter().filter_map(|(output_file, job)| Some((output_file, job["duration"].as_f64()?))).collect());
+}
+
+/// Returns how much memory in kilobytes the last successful job of each output file used.
+pub fn read_peak_rss(build_dir: &str) -> Result<HashMap<String, u64>, String> {
+    let jobs = read_last_jobs(build_dir)?;
+    return Ok(jobs.into_iter().filter_map(|(output_file, job)| Some((output_file, job["peak_rss"].as_u64()?))).collect());
+}
+
+/// Prints the slowest translation units, the most rebuilt files, the average build time per
+/// target and how the last builds went.
+pub fn print_stats(build_dir: &str) -> Result<(), String> {
+    let records = read_records(build_dir)?;
+    let jobs: Vec<&Value> = records.iter().filter(|record| record["type"] == "job").collect();
+    let builds: Vec<&Value> = records.iter().filter(|record| record["type"] == "build").collect();
+    let duration = |job: &Value| job["duration"].as_f64().unwrap_or(0.0);
+
+    // Translation units by their average compile time.
+    let mut sources: HashMap<&str, Vec<&Value>> = HashMap::new();
+    for job in jobs.iter() {
+        if let Some(source) = job["source"].as_str() {
+            sources.entry(source).or_default().push(job);
+        }
+    }
+    let mut slowest_sources: Vec<_> = sources.iter().map(|(source, jobs)| {
+        let average = jobs.iter().map(|job| duration(job)).sum::<f64>() / jobs.len() as f64;
+        let peak_rss = jobs.iter().filter_map(|job| job["peak_rss"].as_u64()).max().unwrap_or(0);
+        return (*source, average, jobs.len(), peak_rss);
+    }).collect();
+    slowest_sources.sort_by(|a, b| b.1.total_cmp(&a.1));
+    println!("Slowest translation units:");
+    for (source, average, count, peak_rss) in slowest_sources.iter().take(NUMBER_OF_ENTRIES) {
+        println!("  {:>8.3} s  {} ({} compiles, peak {} MB)", average, source, count, peak_rss / 1024);
+    }
+
+    let mut rebuilds: HashMap<&str, usize> = HashMap::new();
+    for job in jobs.iter() {
+        *rebuilds.entry(job["output_file"].as_str().unwrap_or_default()).or_default() += 1;
+    }
+    let mut most_rebuilt: Vec<_> = rebuilds.into_iter().collect();
+    most_rebuilt.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("Most rebuilt files:");
+    for (output_file, count) in most_rebuilt.iter().take(NUMBER_OF_ENTRIES) {
+        println!("  {:>8}x  {}", count, output_file);
+    }
+
+    // The time of a target in a build is the time of all its jobs in that build.
+    let mut target_runs: HashMap<&str, HashMap<u64, f64>> = HashMap::new();
+    for job in jobs.iter() {
+        let target = job["target"].as_str().unwrap_or_default();
+        *target_runs.entry(target).or_default().entry(job["run"].as_u64().unwrap_or(0)).or_default() += duration(job);
+    }
+    let mut targets: Vec<_> = target_runs.iter().map(|(target, runs)| (*target, runs.values().sum::<f64>() / runs.len() as f64, runs.len())).collect();
+    targets.sort_by(|a, b| b.1.total_cmp(&a.1));
+    println!("Average build time per target:");
+    for (target, average, count) in targets.iter().take(NUMBER_OF_ENTRIES) {
+        println!("  {:>8.3} s  {} ({} builds)", average, target, count);
+    }
+
+    let mut run_jobs: HashMap<u64, Vec<&Value>> = HashMap::new();
+    for job in jobs.iter() {
+        run_jobs.entry(job["run"].as_u64().unwrap_or(0)).or_default().push(job);
+    }
+    println!("Last builds:");
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    let mut previous_wall_time = None;
+    let first_build = builds.len().saturating_sub(NUMBER_OF_ENTRIES);
+    for (i, build) in builds.iter().enumerate() {
+        let start = build["start"].as_f64().unwrap_or(0.0);
+        let wall_time = build["end"].as_f64().unwrap_or(0.0) - start;
+        let run_jobs = run_jobs.get(&build["run"].as_u64().unwrap_or(0)).map_or(&[][..], |jobs| jobs.as_slice());
+        let job_time = run_jobs.iter().fold(0.0, |job_time, job| job_time + duration(job));
+        let peak_rss = run_jobs.iter().filter_map(|job| job["peak_rss"].as_u64()).max().unwrap_or(0);
+        if i >= first_build {
+            let trend = match previous_wall_time {
+                Some(previous_wall_time) => format!(" ({:+.3} s)", wall_time - previous_wall_time),
+                None => String::new(),
+            };
+            let status = if build["succeeded"].as_bool().unwrap_or(false) { "succeeded" } else { "failed" };
+            println!("  {:>10}  {:>8.3} s{}, {} jobs, {:.3} s job time, peak {} MB, {}", format_age(now - start), wall_time, trend, run_jobs.len(), job_time, peak_rss / 1024, status);
+        }
+        previous_wall_time = Some(wall_time);
+    }
+
+    return Ok(());
+}
+
+fn format_age(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    return match seconds {
+        0..=59 => format!("{} s ago", seconds),
+        60..=3599 => format!("{} min ago", seconds / 60),
+        3600..=86399 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} d ago", seconds / 86400),
+    };
+}
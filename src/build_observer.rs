@@ -0,0 +1,353 @@
+#![allow(clippy::needless_return)]
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration};
+use serde_json::{json, Value};
+use crate::build_log::{BuildLogObserver};
+use crate::critical_path::{CriticalPathObserver};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::diagnostics::{DiagnosticsObserver};
+use crate::work_pool::{WorkResult};
+
+/// A unit of work the scheduler handed to the work pool.
+pub struct Job {
+    // The job id of the work pool.
+    pub id: usize,
+    pub target: Ref<DependencyNode>,
+    // Human readable description, e.g. "Compiling src/main.c".
+    pub description: String,
+    // Why the job had to run.
+    pub reason: String,
+    pub command_line: Vec<String>,
+    // Only set for compile jobs.
+    pub source_file: Option<String>,
+    pub output_file: String,
+}
+
+/// Gets notified about the progress of a build.
+/// All methods have an empty default implementation, so an observer only implements what it needs.
+pub trait BuildObserver {
+    fn configure_started(&mut self) {}
+    /// Called when a phase of configuring finished, e.g. "reading manifests", with how long it took.
+    fn configure_phase_finished(&mut self, _phase: &str, _duration: Duration) {}
+    fn configure_finished(&mut self, _result: Result<&DependencyGraph, &str>) {}
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {}
+    /// Called for every target the build has to build, before any of them starts.
+    fn target_scheduled(&mut self, _graph: &DependencyGraph, _target: Ref<DependencyNode>) {}
+    /// Called when all dependencies of the target are built and its jobs are planned.
+    fn target_started(&mut self, _graph: &DependencyGraph, _target: Ref<DependencyNode>) {}
+    fn job_scheduled(&mut self, _graph: &DependencyGraph, _job: &Job) {}
+    /// Called when a worker of the work pool picks up the job, not called in a dry run.
+    fn job_started(&mut self, _graph: &DependencyGraph, _job: &Job, _worker_id: usize) {}
+    fn job_finished(&mut self, _graph: &DependencyGraph, _job: &Job, _result: &WorkResult) {}
+    /// Called after the scheduler waited for the work pool, with how long it waited.
+    fn scheduler_idle(&mut self, _graph: &DependencyGraph, _duration: Duration) {}
+    /// Called when a target could not be built, its dependents will not be built either.
+    fn target_failed(&mut self, _graph: &DependencyGraph, _target: Ref<DependencyNode>) {}
+    fn build_finished(&mut self, _graph: &DependencyGraph, _succeeded: bool, _number_of_unbuilt_targets: usize) {}
+}
+
+/// Describes the progress of a build as lines of text.
+pub struct LogObserver<F: FnMut(&str)> {
+    log: F,
+}
+
+impl<F: FnMut(&str)> LogObserver<F> {
+    pub fn new(log: F) -> LogObserver<F> {
+        return LogObserver {
+            log,
+        };
+    }
+}
+
+impl<F: FnMut(&str)> BuildObserver for LogObserver<F> {
+    fn build_started(&mut self, graph: &DependencyGraph, targets: &[Ref<DependencyNode>]) {
+        for target in targets {
+            (self.log)(&format!("Scheduler starting from {}", graph.get_name(*target)));
+        }
+    }
+
+    fn job_scheduled(&mut self, _graph: &DependencyGraph, job: &Job) {
+        (self.log)(&format!("{} ({})", job.description, job.reason));
+    }
+
+    fn job_finished(&mut self, _graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        match &result.result {
+            Ok(output) if !output.trim().is_empty() => (self.log)(&format!("Done: {}\n{}", job.description, output.trim_end())),
+            Ok(_) => (self.log)(&format!("Done: {}", job.description)),
+            Err(output) => (self.log)(&format!("Failed: {}\n{}\nerror: {}", job.description, job.command_line.join(" "), output)),
+        }
+    }
+
+    fn target_failed(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        (self.log)(&format!("Target {} failed, not building its dependents", graph.get_name(target)));
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, _succeeded: bool, number_of_unbuilt_targets: usize) {
+        if number_of_unbuilt_targets > 0 {
+            (self.log)(&format!("{} targets could not be built", number_of_unbuilt_targets));
+        }
+    }
+}
+
+/// Shows the progress as a single `[finished/total] description` line that is updated in place
+/// when stdout is a terminal, and as a line per finished job otherwise. The output of a job is
+/// printed as a whole when it finishes, so the output of parallel jobs does not interleave.
+/// The total grows while the build runs, because targets are only planned when they unlock.
+pub struct ProgressObserver {
+    is_terminal: bool,
+    number_of_jobs: usize,
+    number_of_finished_jobs: usize,
+    // The status line has to be erased before anything else is printed.
+    has_status_line: bool,
+}
+
+impl ProgressObserver {
+    pub fn new() -> ProgressObserver {
+        return ProgressObserver {
+            is_terminal: io::stdout().is_terminal(),
+            number_of_jobs: 0,
+            number_of_finished_jobs: 0,
+            has_status_line: false,
+        };
+    }
+
+    /// Prints the text below the status line in a single write.
+    fn print(&mut self, text: &str) {
+        let mut output = String::new();
+        if self.has_status_line {
+            output.push_str("\r\x1b[K");
+            self.has_status_line = false;
+        }
+        output.push_str(text);
+        output.push('\n');
+        let _ = io::stdout().lock().write_all(output.as_bytes());
+    }
+
+    fn print_status(&mut self, description: &str) {
+        let status = format!("[{}/{}] {}", self.number_of_finished_jobs, self.number_of_jobs, description);
+        if !self.is_terminal {
+            self.print(&status);
+            return;
+        }
+        // A status line that wraps can not be replaced anymore.
+        let status: String = status.chars().take(get_terminal_width().saturating_sub(1)).collect();
+        let mut stdout = io::stdout().lock();
+        let _ = write!(stdout, "\r\x1b[K{}", status);
+        let _ = stdout.flush();
+        self.has_status_line = true;
+    }
+}
+
+impl BuildObserver for ProgressObserver {
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {
+        // Watch mode builds again with the same observer.
+        self.number_of_jobs = 0;
+        self.number_of_finished_jobs = 0;
+    }
+
+    fn job_scheduled(&mut self, _graph: &DependencyGraph, _job: &Job) {
+        self.number_of_jobs += 1;
+    }
+
+    fn job_started(&mut self, _graph: &DependencyGraph, job: &Job, _worker_id: usize) {
+        if self.is_terminal {
+            self.print_status(&job.description);
+        }
+    }
+
+    fn job_finished(&mut self, _graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        self.number_of_finished_jobs += 1;
+        match &result.result {
+            Ok(output) if !output.trim().is_empty() => {
+                let status = format!("[{}/{}] {}", self.number_of_finished_jobs, self.number_of_jobs, job.description);
+                self.print(&format!("{}\n{}", status, output.trim_end()));
+            },
+            Ok(_) => self.print_status(&job.description),
+            Err(output) => {
+                self.print(&format!("FAILED: {}\n{}\n{}", job.description, job.command_line.join(" "), output.trim_end()));
+            },
+        }
+    }
+
+    fn target_failed(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        self.print(&format!("Target {} failed, not building its dependents", graph.get_name(target)));
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, _succeeded: bool, number_of_unbuilt_targets: usize) {
+        if number_of_unbuilt_targets > 0 {
+            self.print(&format!("{} targets could not be built", number_of_unbuilt_targets));
+        } else if self.has_status_line {
+            // Keep the last status line.
+            println!();
+            self.has_status_line = false;
+        }
+    }
+}
+
+fn get_terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result != 0 || size.ws_col == 0 {
+        return 80;
+    }
+
+    return size.ws_col as usize;
+}
+
+/// Prints the jobs of a dry run with their commands, as text or as a JSON array.
+pub struct PlanObserver {
+    json: bool,
+    jobs: Vec<Value>,
+    number_of_jobs: usize,
+    // Set when stdout was closed, e.g. by `| head`.
+    is_closed: bool,
+}
+
+impl PlanObserver {
+    pub fn new(json: bool) -> PlanObserver {
+        return PlanObserver {
+            json,
+            jobs: vec![],
+            number_of_jobs: 0,
+            is_closed: false,
+        };
+    }
+
+    /// Writes the text to stdout. A reader that stops reading early is not an error, the rest
+    /// of the plan is dropped.
+    fn print(&mut self, text: &str) {
+        if self.is_closed {
+            return;
+        }
+        if let Err(e) = writeln!(io::stdout().lock(), "{}", text) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("ERROR: Could not write the plan: {}", e);
+            }
+            self.is_closed = true;
+        }
+    }
+}
+
+impl BuildObserver for PlanObserver {
+    fn job_scheduled(&mut self, graph: &DependencyGraph, job: &Job) {
+        self.number_of_jobs += 1;
+        if self.json {
+            self.jobs.push(json!({
+                "target": graph.get_name(job.target),
+                "description": job.description,
+                "reason": job.reason,
+                "command": job.command_line,
+                "output": job.output_file,
+            }));
+        } else {
+            self.print(&format!("{} ({})\n  {}", job.description, job.reason, job.command_line.join(" ")));
+        }
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, _succeeded: bool, _number_of_unbuilt_targets: usize) {
+        if self.json {
+            let plan = serde_json::to_string_pretty(&self.jobs).unwrap();
+            self.print(&plan);
+        } else if self.number_of_jobs == 0 {
+            self.print("Nothing to do");
+        }
+    }
+}
+
+/// Forwards the progress of a build to several observers, in the order they were added.
+#[derive(Default)]
+pub struct ObserverList {
+    observers: Vec<Box<dyn BuildObserver>>,
+}
+
+impl ObserverList {
+    /// The observers every build has, whether it runs in beaver or in the daemon: the output, the
+    /// diagnostics, the build log and the critical path.
+    pub fn for_build(output: Box<dyn BuildObserver>, build_dir: &str) -> ObserverList {
+        let mut observer_list = ObserverList::default();
+        observer_list.add(output);
+        observer_list.add(Box::new(DiagnosticsObserver::new(build_dir)));
+        observer_list.add(Box::new(BuildLogObserver::new(build_dir)));
+        observer_list.add(Box::new(CriticalPathObserver::new()));
+
+        return observer_list;
+    }
+
+    pub fn add(&mut self, observer: Box<dyn BuildObserver>) {
+        self.observers.push(observer);
+    }
+}
+
+impl BuildObserver for ObserverList {
+    fn configure_started(&mut self) {
+        for observer in self.observers.iter_mut() {
+            observer.configure_started();
+        }
+    }
+
+    fn configure_phase_finished(&mut self, phase: &str, duration: Duration) {
+        for observer in self.observers.iter_mut() {
+            observer.configure_phase_finished(phase, duration);
+        }
+    }
+
+    fn configure_finished(&mut self, result: Result<&DependencyGraph, &str>) {
+        for observer in self.observers.iter_mut() {
+            observer.configure_finished(result);
+        }
+    }
+
+    fn build_started(&mut self, graph: &DependencyGraph, targets: &[Ref<DependencyNode>]) {
+        for observer in self.observers.iter_mut() {
+            observer.build_started(graph, targets);
+        }
+    }
+
+    fn target_scheduled(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        for observer in self.observers.iter_mut() {
+            observer.target_scheduled(graph, target);
+        }
+    }
+
+    fn target_started(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        for observer in self.observers.iter_mut() {
+            observer.target_started(graph, target);
+        }
+    }
+
+    fn job_scheduled(&mut self, graph: &DependencyGraph, job: &Job) {
+        for observer in self.observers.iter_mut() {
+            observer.job_scheduled(graph, job);
+        }
+    }
+
+    fn job_started(&mut self, graph: &DependencyGraph, job: &Job, worker_id: usize) {
+        for observer in self.observers.iter_mut() {
+            observer.job_started(graph, job, worker_id);
+        }
+    }
+
+    fn job_finished(&mut self, graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        for observer in self.observers.iter_mut() {
+            observer.job_finished(graph, job, result);
+        }
+    }
+
+    fn scheduler_idle(&mut self, graph: &DependencyGraph, duration: Duration) {
+        for observer in self.observers.iter_mut() {
+            observer.scheduler_idle(graph, duration);
+        }
+    }
+
+    fn target_failed(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        for observer in self.observers.iter_mut() {
+            observer.target_failed(graph, target);
+        }
+    }
+
+    fn build_finished(&mut self, graph: &DependencyGraph, succeeded: bool, number_of_unbuilt_targets: usize) {
+        for observer in self.observers.iter_mut() {
+            observer.build_finished(graph, succeeded, number_of_unbuilt_targets);
+        }
+    }
+}
@@ -1,8 +1,11 @@
+#![allow(clippy::needless_return)]
 use std::collections::HashMap;
-use std::path::{Path};
+use crate::compile_commands::{get_compile_instruction};
 use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyType, DependencyOptions, Ref};
 use crate::graph_walker::{GraphVisitor};
-use crate::work_pool::{WorkPool, WorkInstruction};
+use crate::language::{get_link_language};
+use crate::profile::{Profile};
+use crate::work_pool::{WorkPool, WorkInstruction, get_object_file};
 
 /// Caches build information for this target. 
 struct LibraryNodeCache {
@@ -13,42 +16,45 @@ struct LibraryNodeCache {
 pub struct Builder<'a> {
     work_pool: &'a mut WorkPool,
     build_dir: String,
-    headers: Vec<Vec<String>>, // Stack of header files
+    profile: Profile,
     objects: Vec<Vec<String>>, // Stack of object files
-    library_cache: HashMap<Ref<DependencyNode>, LibraryNodeCache>
+    library_cache: HashMap<Ref<DependencyNode>, LibraryNodeCache>,
+    has_failed: bool,
 }
 
 impl Builder<'_> {
-    pub fn new(build_dir: String, work_pool: &mut WorkPool) -> Builder {
+    pub fn new(build_dir: String, profile: Profile, work_pool: &mut WorkPool) -> Builder<'_> {
         return Builder {
             work_pool,
             build_dir,
-            headers: vec![],
+            profile,
             objects: vec![],
             library_cache: HashMap::new(),
+            has_failed: false,
         };
     }
 
     /// Reset the internal state of the builder so it can perform another build.
     pub fn reset(&mut self) {
-        self.headers.clear();
         self.objects.clear();
     }
+
+    /// Whether a job failed in any of the builds since the builder was created.
+    pub fn has_failed(&self) -> bool {
+        return self.has_failed;
+    }
 }
 
 impl GraphVisitor for Builder<'_> {
     fn visit_pre_dependency(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>) {
-        let name = graph.get_name(node);
         match graph.get_type(node) {
-            DependencyType::INTERFACE => {
+            DependencyType::INTERFACE | DependencyType::IMPORTED | DependencyType::CUSTOM => {
             },
             DependencyType::LIBRARY => {
-                self.headers.push(vec![]);
                 self.objects.push(vec![]);
             }
-            DependencyType::EXECUTABLE => {
+            DependencyType::EXECUTABLE | DependencyType::TEST => {
                 // TODO, implement caching for executables.
-                self.headers.push(vec![]);
                 self.objects.push(vec![]);
             },
         }
@@ -59,20 +65,41 @@ impl GraphVisitor for Builder<'_> {
 
         match graph.get_type(node) {
             DependencyType::INTERFACE => {
-                let headers = graph.get_files(node);
-                let header_path = Path::new(headers.first().unwrap());
-                let include_dir = header_path.parent().unwrap();
-                self.headers.last_mut().unwrap().push(include_dir.to_str().unwrap().to_owned());
+            },
+            DependencyType::CUSTOM => {
+                // Libraries and their interfaces both require the command, it only has to run once.
+                if self.library_cache.contains_key(&node) {
+                    return;
+                }
+                // The walk order is static, discovered edges are only honored by the scheduler.
+                let Some(DependencyOptions::CustomCommandOptions { command, working_dir, outputs, .. }) = graph.get_options(node) else {
+                    return;
+                };
+                println!("Generating {}", outputs.join(", "));
+                let job_id = self.work_pool.schedule_work(WorkInstruction::Custom { command, working_dir, outputs });
+                let is_built = match self.work_pool.get_result_blocking(job_id) {
+                    Ok(output) => {
+                        print_output(&output);
+                        true
+                    },
+                    Err(output) => {
+                        println!("Failed to generate, error: {}", output);
+                        self.has_failed = true;
+                        false
+                    },
+                };
+                self.library_cache.insert(node, LibraryNodeCache { is_built, objects: vec![] });
+            },
+            DependencyType::IMPORTED => {
+                self.objects.last_mut().unwrap().extend(graph.get_files(node));
             },
             DependencyType::LIBRARY => {
-                // TODO, move to base handler.
-                let headers = self.headers.pop().unwrap();
+                let requirements = graph.get_usage_requirements(node);
 
                 // TODO, move to cache handler.
                 if self.library_cache.contains_key(&node) && self.library_cache[&node].is_built {
                     let objects = self.library_cache[&node].objects.clone();
                     self.objects.last_mut().unwrap().extend(objects);
-                    self.headers.last_mut().unwrap().extend(headers);
                     return; // Library is already built, return cached objects.
                 }
 
@@ -81,15 +108,9 @@ impl GraphVisitor for Builder<'_> {
                 let mut objects = vec![];
                 let sources = graph.get_files(node);
                 for source in sources {
-                    let source_path = Path::new(&source);
-                    let source_name = source_path.file_name().unwrap().to_str().unwrap().to_owned(); 
-                    let object_file = format!("{}/{}.o", self.build_dir, source_name);
+                    let object_file = get_object_file(&self.build_dir, &source);
                     println!("Compiling {}", source);
-                    let compile_instruction = WorkInstruction::Compile {
-                        source_file: source.clone(),
-                        include_dirs: headers.clone(),
-                        output_file: object_file.clone(),
-                    };
+                    let compile_instruction = get_compile_instruction(&self.profile, &self.build_dir, &requirements, &source);
                     let job_id = self.work_pool.schedule_work(compile_instruction);
                     job_ids.push(job_id);
                     objects.push(object_file);
@@ -98,11 +119,13 @@ impl GraphVisitor for Builder<'_> {
                 let mut target_built = true;
                 for job_id in job_ids {
                     match self.work_pool.get_result_blocking(job_id) {
-                        Ok(_) => {
+                        Ok(output) => {
+                            print_output(&output);
                             continue;
                         },
                         Err(output) => {
                             target_built = false;
+                            self.has_failed = true;
                             println!("Failed to compile, error: {}", output);
                         }
                     }
@@ -120,34 +143,29 @@ impl GraphVisitor for Builder<'_> {
 
                 // TODO, move to base.
                 self.objects.last_mut().unwrap().extend(objects);
-                self.headers.last_mut().unwrap().extend(headers);
             },
-            DependencyType::EXECUTABLE => {
-                let headers = self.headers.pop().unwrap();
+            DependencyType::EXECUTABLE | DependencyType::TEST => {
+                let requirements = graph.get_usage_requirements(node);
                 // Step 1, build our own sources.
                 let mut own_objects = vec![];
                 let sources = graph.get_files(node);
                 for source in sources {
                     println!("Compiling executable source: {}", source);
-                    let source_path = Path::new(&source);
-                    let source_name = source_path.file_name().unwrap().to_str().unwrap().to_owned(); 
-                    let object_file = format!("{}/{}.o", self.build_dir, source_name);
-
-                    let compile_instruction = WorkInstruction::Compile {
-                        source_file: source.clone(),
-                        include_dirs: headers.clone(),
-                        output_file: object_file.clone(),
-                    };
+                    let object_file = get_object_file(&self.build_dir, &source);
+
+                    let compile_instruction = get_compile_instruction(&self.profile, &self.build_dir, &requirements, &source);
                     let job_id = self.work_pool.schedule_work(compile_instruction);
                     match self.work_pool.get_result_blocking(job_id) {
-                        Ok(_) => {
+                        Ok(output) => {
                             println!("Compiled {}", source);
+                            print_output(&output);
                         },
                         Err(output) => {
                             // TODO, mark target as failed so that targets depending on this one
                             // will not be build.
                             println!("Failed to compile {}, error: {}", source, output);
                             println!("ABORTING");
+                            self.has_failed = true;
                             return;
                         }
                     }
@@ -163,35 +181,46 @@ impl GraphVisitor for Builder<'_> {
                     objects.extend(dependency_objects.clone());
                 }
 
-                // TODO, add link flags.
                 // Step 3, execute the linker to combine all object files into one executable
                 let executable_file = format!("{}/{}", self.build_dir, name);
 
-                let executable_options = graph.get_options(node).unwrap();
-                let link_libraries = if let DependencyOptions::ExecutableOptions { link_flags, link_libraries } = executable_options {
-                    link_libraries.clone()
-                } else {
-                    vec![]
+                let (mut link_flags, mut link_libraries, linker_script) = match graph.get_options(node) {
+                    Some(DependencyOptions::ExecutableOptions { link_flags, link_libraries, linker_script }) => (link_flags, link_libraries, linker_script),
+                    _ => (vec![], vec![], None),
                 };
+                link_flags.extend(requirements.link_options);
+                link_libraries.extend(requirements.link_libraries);
 
                 let link_instruction = WorkInstruction::Link {
+                    language: get_link_language(&objects),
                     object_files: objects,
-                    link_libraries: link_libraries,
+                    link_dirs: requirements.link_dirs,
+                    link_flags,
+                    linker_script,
+                    link_libraries,
                     output_file: executable_file.clone(),
                 };
                 let job_id = self.work_pool.schedule_work(link_instruction);
                 match self.work_pool.get_result_blocking(job_id) {
-                    Ok(_) => {
+                    Ok(output) => {
                         println!("Linked {}", executable_file);
+                        print_output(&output);
                     },
                     Err(output) => {
                         // TODO, mark target as failed so that targets depending on this one
                         // will not be build.
-                        // TODO, mark build as failed.
                         println!("Failed to link {}, error: {}", executable_file, output);
+                        self.has_failed = true;
                     }
                 }
             },
         }
     }
 }
+
+/// Prints the output of a successful job, e.g. the warnings of the compiler.
+fn print_output(output: &str) {
+    if !output.trim().is_empty() {
+        println!("{}", output.trim_end());
+    }
+}
@@ -0,0 +1,187 @@
+// Parses the command line arguments of beaver.
+#![allow(clippy::needless_return)]
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Subcommand {
+    Build,
+    Watch,
+    Daemon,
+    Test,
+    Run,
+    Install,
+    Uninstall,
+    // Writes compile_commands.json without compiling anything.
+    CompileCommands,
+    // Summarizes the build log.
+    Stats,
+    // Predicts the build time for different numbers of workers from the build log.
+    Simulate,
+}
+
+/// How results meant for other programs are printed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct Arguments {
+    pub subcommand: Subcommand,
+    // Names of the targets to build, all roots are built when empty.
+    pub targets: Vec<String>,
+    pub sequential: bool,
+    // Print the jobs that would run instead of running them.
+    pub dry_run: bool,
+    pub format: OutputFormat,
+    // Stop a running daemon instead of starting one.
+    pub stop: bool,
+    pub profile: String,
+    // Write the progress of the build as JSON lines to this file or file descriptor.
+    pub events: Option<String>,
+    // Write the timeline of the build in the Chrome trace format to this file.
+    pub trace: Option<String>,
+    // Only run the tests matching the filter.
+    pub filter: Option<String>,
+    // Timeout of every test in seconds, overrides the timeouts of the packages.
+    pub timeout: Option<u64>,
+    // Write the test results as JUnit XML to this file.
+    pub junit: Option<String>,
+    // Passed to the executable that is run, everything after "--".
+    pub run_arguments: Vec<String>,
+    // Where to install to.
+    pub prefix: String,
+    // The number of jobs to run at once, when not given by make.
+    pub jobs: Option<usize>,
+    // Do not start jobs while the load average is this high.
+    pub max_load: Option<f64>,
+    // In megabytes, the memory the jobs running at once may use together.
+    pub memory_budget: Option<u64>,
+    // The numbers of workers to simulate the build with.
+    pub job_counts: Vec<usize>,
+}
+
+/// Parses arguments of the form `beaver [build|watch|daemon|test|run|install|uninstall|compile-commands|stats|simulate] [options] [targets] [-- arguments]`.
+pub fn parse_arguments(args: Vec<String>) -> Result<Arguments, String> {
+    let mut arguments = Arguments {
+        subcommand: Subcommand::Build,
+        targets: vec![],
+        sequential: false,
+        dry_run: false,
+        format: OutputFormat::Text,
+        stop: false,
+        profile: "debug".to_owned(),
+        events: None,
+        trace: None,
+        filter: None,
+        timeout: None,
+        junit: None,
+        run_arguments: vec![],
+        prefix: "/usr/local".to_owned(),
+        jobs: None,
+        max_load: None,
+        memory_budget: None,
+        job_counts: vec![1, 2, 4, 8, 16],
+    };
+
+    let mut args = args.into_iter().peekable();
+    if let Some(subcommand) = args.peek() {
+        let subcommand = match subcommand.as_str() {
+            "build" => Some(Subcommand::Build),
+            "watch" => Some(Subcommand::Watch),
+            "daemon" => Some(Subcommand::Daemon),
+            "test" => Some(Subcommand::Test),
+            "run" => Some(Subcommand::Run),
+            "install" => Some(Subcommand::Install),
+            "uninstall" => Some(Subcommand::Uninstall),
+            "compile-commands" => Some(Subcommand::CompileCommands),
+            "stats" => Some(Subcommand::Stats),
+            "simulate" => Some(Subcommand::Simulate),
+            _ => None,
+        };
+        if let Some(subcommand) = subcommand {
+            arguments.subcommand = subcommand;
+            args.next();
+        }
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                arguments.profile = args.next().ok_or("Missing profile name after --profile")?;
+            },
+            "--sequential" if arguments.subcommand == Subcommand::Build => {
+                arguments.sequential = true;
+            },
+            "--events" => {
+                arguments.events = Some(args.next().ok_or("Missing file after --events")?);
+            },
+            "--trace" => {
+                arguments.trace = Some(args.next().ok_or("Missing file after --trace")?);
+            },
+            "--dry-run" if arguments.subcommand == Subcommand::Build => {
+                arguments.dry_run = true;
+            },
+            "--format" => {
+                arguments.format = match args.next().ok_or("Missing format after --format")?.as_str() {
+                    "text" => OutputFormat::Text,
+                    "json" => OutputFormat::Json,
+                    format => return Err(format!("Unknown format: {}", format)),
+                };
+            },
+            "--stop" if arguments.subcommand == Subcommand::Daemon => {
+                arguments.stop = true;
+            },
+            "--filter" if arguments.subcommand == Subcommand::Test => {
+                arguments.filter = Some(args.next().ok_or("Missing filter after --filter")?);
+            },
+            "--timeout" if arguments.subcommand == Subcommand::Test => {
+                let timeout = args.next().ok_or("Missing seconds after --timeout")?;
+                arguments.timeout = Some(timeout.parse().map_err(|_| format!("Invalid timeout: {}", timeout))?);
+            },
+            "--junit" if arguments.subcommand == Subcommand::Test => {
+                arguments.junit = Some(args.next().ok_or("Missing file after --junit")?);
+            },
+            "--prefix" if arguments.subcommand == Subcommand::Install => {
+                arguments.prefix = args.next().ok_or("Missing directory after --prefix")?;
+            },
+            "-j" if arguments.subcommand == Subcommand::Simulate => {
+                let job_counts = args.next().ok_or("Missing job counts after -j")?;
+                arguments.job_counts = job_counts.split(',').map(|count| match count.parse() {
+                    Ok(count) if count > 0 => Ok(count),
+                    _ => Err(format!("Invalid job count: {}", count)),
+                }).collect::<Result<_, _>>()?;
+            },
+            "-j" | "--jobs" => {
+                let jobs = args.next().ok_or("Missing number of jobs after -j")?;
+                arguments.jobs = match jobs.parse() {
+                    Ok(jobs) if jobs > 0 => Some(jobs),
+                    _ => return Err(format!("Invalid number of jobs: {}", jobs)),
+                };
+            },
+            "-l" | "--load-average" => {
+                let max_load = args.next().ok_or("Missing load after -l")?;
+                arguments.max_load = Some(max_load.parse().map_err(|_| format!("Invalid load: {}", max_load))?);
+            },
+            "--memory-budget" => {
+                let memory_budget = args.next().ok_or("Missing megabytes after --memory-budget")?;
+                arguments.memory_budget = Some(memory_budget.parse().map_err(|_| format!("Invalid memory budget: {}", memory_budget))?);
+            },
+            "--" if arguments.subcommand == Subcommand::Run => {
+                arguments.run_arguments = args.by_ref().collect();
+            },
+            option if option.starts_with('-') => {
+                return Err(format!("Unknown option: {}", option));
+            },
+            target => {
+                arguments.targets.push(target.to_owned());
+            },
+        }
+    }
+
+    if arguments.subcommand == Subcommand::Run && arguments.targets.len() != 1 {
+        return Err("Run needs exactly one target".to_owned());
+    }
+
+    return Ok(arguments);
+}
@@ -0,0 +1,60 @@
+// Writes compile_commands.json, so clangd and other tools compile the sources like beaver does.
+// https://clang.llvm.org/docs/JSONCompilationDatabase.html
+#![allow(clippy::needless_return)]
+use std::env;
+use std::fs;
+use serde_json::{json, Value};
+use crate::dependency_graph::{DependencyGraph, DependencyType, UsageRequirements};
+use crate::language::{Language};
+use crate::profile::{Profile};
+use crate::work_pool::{WorkInstruction, get_object_file};
+
+pub fn get_compile_commands_file(build_dir: &str) -> String {
+    return format!("{}/compile_commands.json", build_dir);
+}
+
+/// The instruction compiling the source of a target with the given requirements. Shared by the
+/// builder, the scheduler and the compilation database, so they all use the same arguments.
+pub fn get_compile_instruction(profile: &Profile, build_dir: &str, requirements: &UsageRequirements, source: &str) -> WorkInstruction {
+    return WorkInstruction::Compile {
+        language: Language::from_source(source).unwrap(),
+        source_file: source.to_owned(),
+        include_dirs: requirements.include_dirs.clone(),
+        defines: profile.get_defines(requirements),
+        flags: profile.get_compile_flags(requirements),
+        output_file: get_object_file(build_dir, source),
+    };
+}
+
+/// Writes the compile commands of all sources in the graph. The file is only written when the
+/// commands changed, so tools watching it do not reload for nothing.
+/// Returns whether the file was written.
+pub fn write_compile_commands(graph: &DependencyGraph, profile: &Profile, build_dir: &str) -> Result<bool, String> {
+    // The paths in the commands are relative to where beaver runs.
+    let directory = env::current_dir().map_err(|e| format!("Could not get the current directory: {}", e))?;
+    let mut entries = vec![];
+    for node in graph.get_nodes() {
+        if !matches!(graph.get_type(node), DependencyType::LIBRARY | DependencyType::EXECUTABLE | DependencyType::TEST) {
+            continue;
+        }
+        let requirements = graph.get_usage_requirements(node);
+        for source in graph.get_files(node) {
+            let instruction = get_compile_instruction(profile, build_dir, &requirements, &source);
+            entries.push(json!({
+                "directory": directory.display().to_string(),
+                "arguments": instruction.get_command_line(),
+                "file": source,
+                "output": instruction.get_output_file(),
+            }));
+        }
+    }
+
+    let content = serde_json::to_string_pretty(&Value::Array(entries)).unwrap();
+    let compile_commands_file = get_compile_commands_file(build_dir);
+    if fs::read_to_string(&compile_commands_file).is_ok_and(|old_content| old_content == content) {
+        return Ok(false);
+    }
+    fs::write(&compile_commands_file, content).map_err(|e| format!("Could not write {}: {}", compile_commands_file, e))?;
+
+    return Ok(true);
+}
@@ -1,98 +1,386 @@
 // Configurator reads project structure and creates a dependency graph
-use serde_json::{Result, Value};
-use crate::dependency_graph::{DependencyGraph, DependencyOptions};
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap};
+use std::fs;
+use std::path::{Path};
+use std::time::{Instant};
+use serde_json::{Value};
+use crate::build_observer::{BuildObserver};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyOptions, Ref, Visibility};
 use crate::filesystem::{DirReader};
+use crate::language::{HEADER_EXTENSIONS, SOURCE_EXTENSIONS};
+use crate::pkg_config::{find_system_dependency};
 
-/// Loads a project based on a predefined structure and clib package.json files
-pub fn configure_clib_project(directory: &str) -> DependencyGraph {
-    let mut dependency_graph = DependencyGraph::new();
+/// A dependency in the deps dir, with its files and its manifest.
+struct Package {
+    dir: String,
+    dir_contents: DirReader,
+    manifest: Value,
+}
 
+/// Loads a project based on a predefined structure and clib package.json files.
+/// Configuring runs in phases: reading the manifests, resolving the system dependencies with
+/// pkg-config and building the graph, the observer is told how long each phase took.
+pub fn configure_clib_project(directory: &str, observer: &mut dyn BuildObserver) -> Result<DependencyGraph, String> {
+    let phase_start = Instant::now();
+    let project_manifest = read_project_manifest(directory)?;
     let src_dir = format!("{}/src", directory);
     let src_dir_contents = DirReader::new_for(&src_dir);
+    let common_dir = format!("{}/src/common", directory);
+    let common_dir_contents = DirReader::new_for(&common_dir);
+    let mut packages = vec![];
+    for dep_dir in DirReader::get_subdirs(&format!("{}/deps", directory)) {
+        let dir_contents = DirReader::new_for(&dep_dir);
+        let manifest = read_manifest(&dep_dir, &dir_contents)?;
+        packages.push(Package { dir: dep_dir, dir_contents, manifest });
+    }
+    observer.configure_phase_finished("reading manifests", phase_start.elapsed());
+
+    // System libraries are declared by their pkg-config name, e.g. "system_dependencies": ["libcurl"]
+    let phase_start = Instant::now();
+    let mut dependency_graph = DependencyGraph::new();
+    let mut system_dependencies = HashMap::new();
+    for manifest in [&project_manifest].into_iter().chain(packages.iter().map(|package| &package.manifest)) {
+        for package in get_strings(&manifest["system_dependencies"]) {
+            let system_dependency = find_system_dependency(&mut dependency_graph, &package)?;
+            system_dependencies.insert(package, system_dependency);
+        }
+    }
+    observer.configure_phase_finished("resolving pkg-config", phase_start.elapsed());
+
+    let phase_start = Instant::now();
+    let dependency_graph = build_graph(dependency_graph, directory, &project_manifest, &src_dir_contents, &common_dir_contents, packages, &system_dependencies)?;
+    observer.configure_phase_finished("building the graph", phase_start.elapsed());
+
+    return Ok(dependency_graph);
+}
+
+/// Adds the targets of the project and its dependencies to the graph, which holds the system
+/// dependencies already.
+fn build_graph(mut dependency_graph: DependencyGraph, directory: &str, project_manifest: &Value, src_dir_contents: &DirReader, common_dir_contents: &DirReader, packages: Vec<Package>, system_dependencies: &HashMap<String, Ref<DependencyNode>>) -> Result<DependencyGraph, String> {
 
     // All executable require this interface
-    let root_interface = dependency_graph.add_interface("clib_headers", src_dir_contents.get_files_with_extension("h"));
+    let root_interface = dependency_graph.add_interface("clib_headers", get_header_files(src_dir_contents));
 
-    // Common dir is sort of a dependency dir.
-    let common_dir = format!("{}/src/common", directory);
-    let common_dir_contents = DirReader::new_for(&common_dir);
-    let common_library = dependency_graph.add_library("common_lib", common_dir_contents.get_files_with_extension("c"));
-    let common_interface = dependency_graph.add_interface("common_headers", common_dir_contents.get_files_with_extension("h"));
-    dependency_graph.add_requirement(common_library, common_interface);
+    // Common dir is sort of a dependency dir, the files generated by the project are part of it.
+    let (custom_commands, generated_files) = add_custom_commands(&mut dependency_graph, directory, project_manifest)?;
+    let mut common_sources = get_source_files(common_dir_contents);
+    common_sources.extend(generated_files.iter().filter(|file| has_extension(file, &SOURCE_EXTENSIONS)).cloned());
+    let mut common_headers = get_header_files(common_dir_contents);
+    common_headers.extend(generated_files.iter().filter(|file| has_extension(file, &HEADER_EXTENSIONS)).cloned());
+    let common_library = dependency_graph.add_library("common_lib", common_sources);
+    let common_interface = dependency_graph.add_interface("common_headers", common_headers);
+    dependency_graph.add_requirement(common_library, common_interface, Visibility::PUBLIC);
+    for custom_command in custom_commands {
+        dependency_graph.add_requirement(common_library, custom_command, Visibility::PRIVATE);
+        dependency_graph.add_requirement(common_interface, custom_command, Visibility::PRIVATE);
+    }
+
+    let mut imported_libraries = vec![];
+    for package in get_strings(&project_manifest["system_dependencies"]) {
+        imported_libraries.push(system_dependencies[&package]);
+    }
+    for prebuilt in project_manifest["prebuilt"].as_array().into_iter().flatten() {
+        imported_libraries.push(add_prebuilt_library(&mut dependency_graph, directory, prebuilt)?);
+    }
 
     let mut roots = vec![];
     // All files in the root directories are executables and should be build.
-    for executable_src in src_dir_contents.get_files_with_extension("c") {
-        let split_by_slash: Vec<_> = executable_src.trim_end_matches(".c").split("/").collect();
-        let executable_name = split_by_slash.last().unwrap();
-        let executable = dependency_graph.add_executable(&executable_name, vec![executable_src.clone()]);
-        // TODO, add a more modular way to configure this globally
+    for executable_src in get_source_files(src_dir_contents) {
+        let file_name = executable_src.split("/").last().unwrap();
+        let executable_name = file_name.rsplit_once('.').unwrap().0;
+        let executable = dependency_graph.add_executable(executable_name, vec![executable_src.clone()]);
         let executable_options = DependencyOptions::ExecutableOptions {
-            link_libraries: vec!["curl".to_string()],
-            link_flags: vec![],
+            link_libraries: get_strings(&project_manifest["build"]["link_libraries"]),
+            link_flags: get_strings(&project_manifest["build"]["link_options"]),
+            linker_script: project_manifest["build"]["linker_script"].as_str().map(|linker_script| format!("{}/{}", directory, linker_script)),
         };
         dependency_graph.set_executable_options(executable, executable_options);
-        dependency_graph.add_requirement(executable, root_interface);
-        dependency_graph.add_requirement(executable, common_library);
+        for link_dir in get_strings(&project_manifest["build"]["link_dirs"]) {
+            dependency_graph.add_link_dir(executable, &format!("{}/{}", directory, link_dir), Visibility::PRIVATE);
+        }
+        dependency_graph.add_requirement(executable, root_interface, Visibility::PRIVATE);
+        dependency_graph.add_requirement(executable, common_library, Visibility::PRIVATE);
+        for imported_library in imported_libraries.iter() {
+            dependency_graph.add_requirement(executable, *imported_library, Visibility::PRIVATE);
+        }
 
         roots.push(executable);
     }
 
-    // A bit hacky, but need to include the deps folder.
-    let deps_dir_dummy = format!("{}/deps/dummy.h", directory);
-    let deps_interface = dependency_graph.add_interface("deps_headers", vec![deps_dir_dummy]);
-    dependency_graph.add_requirement(common_library, deps_interface);
+    // Clib headers includes are of the form "dep_name/header.h", so the deps folder is an include dir.
+    let deps_interface = dependency_graph.add_interface("deps_headers", vec![]);
+    dependency_graph.add_include_dir(deps_interface, &format!("{}/deps", directory), Visibility::INTERFACE);
+    dependency_graph.add_requirement(common_library, deps_interface, Visibility::PUBLIC);
 
     let mut unresolved_dependencies = vec![];
 
     // First pass, loop trough dependencies and create lib and interface targets for them
-    let deps_dir_name = format!("{}/deps", directory);
-    let dep_dirs = DirReader::get_subdirs(&deps_dir_name);
-    for dep_dir in dep_dirs {
-        let dep_dir_contents = DirReader::new_for(&dep_dir);
+    for Package { dir: dep_dir, dir_contents: dep_dir_contents, manifest } in packages {
+
+        // The manifest lists the files of the package, otherwise take everything in the directory.
+        let (mut sources, mut headers): (Vec<_>, Vec<_>) = match get_manifest_files(&dep_dir, &manifest) {
+            Some(files) => {
+                let sources = files.iter().filter(|file| has_extension(file, &SOURCE_EXTENSIONS)).cloned().collect();
+                let headers = files.iter().filter(|file| has_extension(file, &HEADER_EXTENSIONS)).cloned().collect();
+                (sources, headers)
+            },
+            None => (get_source_files(&dep_dir_contents), get_header_files(&dep_dir_contents)),
+        };
+        // Clib packages ship their tests as test.c, they are not part of the library.
+        let test_sources: Vec<_> = get_source_files(&dep_dir_contents).into_iter().filter(|file| is_test_file(file)).collect();
+        sources.retain(|file| !is_test_file(file));
+        let (custom_commands, generated_files) = add_custom_commands(&mut dependency_graph, &dep_dir, &manifest)?;
+        sources.extend(generated_files.iter().filter(|file| has_extension(file, &SOURCE_EXTENSIONS)).cloned());
+        headers.extend(generated_files.iter().filter(|file| has_extension(file, &HEADER_EXTENSIONS)).cloned());
 
         let dep_name = format!("{}_lib", dep_dir);
-        let dep = dependency_graph.add_library(&dep_name, dep_dir_contents.get_files_with_extension("c"));
-        dependency_graph.add_requirement(common_library, dep);
+        let dep = dependency_graph.add_library(&dep_name, sources);
+        dependency_graph.add_requirement(common_library, dep, Visibility::PUBLIC);
         let dep_interface_name = format!("{}_headers", dep_dir);
-        let dep_interface = dependency_graph.add_interface(&dep_interface_name, dep_dir_contents.get_files_with_extension("h"));
-        dependency_graph.add_requirement(dep, dep_interface);
+        let dep_interface = dependency_graph.add_interface(&dep_interface_name, headers);
+        dependency_graph.add_requirement(dep, dep_interface, Visibility::PUBLIC);
+        // Generation runs before anything that compiles the generated sources or includes the generated headers.
+        for custom_command in custom_commands {
+            dependency_graph.add_requirement(dep, custom_command, Visibility::PRIVATE);
+            dependency_graph.add_requirement(dep_interface, custom_command, Visibility::PRIVATE);
+        }
 
-        let dependencies = get_clib_dependencies(&dep_dir_contents);
-        if !dependencies.is_empty() { // Clib headers includes are of the form "dep_name/header.h"
-            dependency_graph.add_requirement(dep, deps_interface);
+        add_usage_requirements(&mut dependency_graph, dep, dep_interface, &dep_dir, &manifest);
+        for package in get_strings(&manifest["system_dependencies"]) {
+            dependency_graph.add_requirement(dep, system_dependencies[&package], Visibility::PUBLIC);
+        }
+        for prebuilt in manifest["prebuilt"].as_array().into_iter().flatten() {
+            let prebuilt_library = add_prebuilt_library(&mut dependency_graph, &dep_dir, prebuilt)?;
+            dependency_graph.add_requirement(dep, prebuilt_library, Visibility::PUBLIC);
         }
 
+        let dependencies = get_clib_dependencies(&manifest);
+        if !dependencies.is_empty() {
+            dependency_graph.add_requirement(dep, deps_interface, Visibility::PUBLIC);
+        }
+
+        let test = if test_sources.is_empty() {
+            None
+        } else {
+            let dep_base_name = dep_dir.rsplit('/').next().unwrap_or(&dep_dir);
+            let test_options = DependencyOptions::TestOptions {
+                working_dir: dep_dir.clone(),
+                timeout_seconds: manifest["test_timeout"].as_u64(),
+            };
+            let test = dependency_graph.add_test(&format!("{}_test", dep_base_name), test_sources, test_options);
+            dependency_graph.add_requirement(test, dep, Visibility::PRIVATE);
+            Some(test)
+        };
+
         for dependency in dependencies {
-            unresolved_dependencies.push((dep, dependency));
+            unresolved_dependencies.push((dep, test, dependency));
         }
     }
 
     // Second pass, add interface targets as requirements for libs
-    for (origin, dependency) in unresolved_dependencies {
+    let mut dependency_libraries: HashMap<Ref<DependencyNode>, Vec<Ref<DependencyNode>>> = HashMap::new();
+    let mut tests = vec![];
+    for (origin, test, dependency) in unresolved_dependencies {
         let dependency_interface_name = format!("{}_headers", dependency);
         match dependency_graph.find_interface(&dependency_interface_name) {
             Some(dependency_interface) => {
-                dependency_graph.add_requirement(origin, dependency_interface);
+                dependency_graph.add_requirement(origin, dependency_interface, Visibility::PUBLIC);
             },
             None => {
-                panic!("Missing dependency: {} for {} {:#?}", dependency, dependency_graph.get_name(origin), dependency_graph);
+                return Err(format!("Missing dependency: {} for {}", dependency, dependency_graph.get_name(origin)));
             }
         }
+        if let Some(dependency_library) = dependency_graph.find_library(&format!("{}_lib", dependency)) {
+            dependency_libraries.entry(origin).or_default().push(dependency_library);
+        }
+        if let Some(test) = test.filter(|test| !tests.contains(&(*test, origin))) {
+            tests.push((test, origin));
+        }
     }
 
-    return dependency_graph;
+    // Tests are linked on their own, so they need the objects of the libraries their package
+    // depends on, transitively. The libraries themselves do not require each other, so they can
+    // be compiled at the same time.
+    for (test, library) in tests {
+        let mut visited = vec![library];
+        let mut pending = vec![library];
+        while let Some(library) = pending.pop() {
+            for dependency_library in dependency_libraries.get(&library).into_iter().flatten() {
+                if !visited.contains(dependency_library) {
+                    visited.push(*dependency_library);
+                    pending.push(*dependency_library);
+                    dependency_graph.add_requirement(test, *dependency_library, Visibility::PRIVATE);
+                }
+            }
+        }
+    }
+
+    return Ok(dependency_graph);
+}
+
+/// Reads the package.json in the root of the project.
+pub fn read_project_manifest(directory: &str) -> Result<Value, String> {
+    return read_manifest(directory, &DirReader::new_for(directory));
 }
 
-fn get_clib_dependencies(dir_contents: &DirReader) -> Vec<String> {
+/// Returns the directories whose contents determine the structure of a clib project.
+pub fn get_clib_source_dirs(directory: &str) -> Vec<String> {
+    let mut source_dirs = vec![
+        format!("{}/src", directory),
+        format!("{}/src/common", directory),
+        format!("{}/deps", directory),
+    ];
+    source_dirs.extend(DirReader::get_subdirs(&format!("{}/deps", directory)));
+
+    return source_dirs;
+}
+
+/// Returns whether the file would be picked up when configuring a project.
+pub fn is_project_file(file: &str) -> bool {
+    return has_extension(file, &SOURCE_EXTENSIONS) || has_extension(file, &HEADER_EXTENSIONS) || file.ends_with("package.json");
+}
+
+fn is_test_file(file: &str) -> bool {
+    let file_name = file.rsplit('/').next().unwrap_or(file);
+    return file_name.rsplit_once('.').is_some_and(|(stem, _)| stem == "test");
+}
+
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    return extensions.iter().any(|extension| file.ends_with(&format!(".{}", extension)));
+}
+
+fn get_source_files(dir_contents: &DirReader) -> Vec<String> {
+    return SOURCE_EXTENSIONS.iter().flat_map(|extension| dir_contents.get_files_with_extension(extension)).collect();
+}
+
+fn get_header_files(dir_contents: &DirReader) -> Vec<String> {
+    return HEADER_EXTENSIONS.iter().flat_map(|extension| dir_contents.get_files_with_extension(extension)).collect();
+}
+
+/// Reads the package.json of a clib package, Null when the package has none.
+fn read_manifest(dir: &str, dir_contents: &DirReader) -> Result<Value, String> {
     if !dir_contents.has_file("package.json") {
-        return vec![];
+        return Ok(Value::Null);
     }
     let config_file_content = dir_contents.get_file_contents("package.json");
-    let config_file_json: Value = serde_json::from_str(&config_file_content).unwrap();
+    // A manifest that is being edited can be invalid, watch mode and the daemon have to survive it.
+    return serde_json::from_str(&config_file_content).map_err(|e| format!("Invalid {}/package.json: {}", dir, e));
+}
+
+/// Returns the files in the "src" field of the manifest, the paths are relative to the package.
+fn get_manifest_files(dir: &str, manifest: &Value) -> Option<Vec<String>> {
+    let files = manifest["src"].as_array()?;
+    return Some(files.iter().filter_map(|file| file.as_str()).map(|file| format!("{}/{}", dir, file)).collect());
+}
+
+/// Adds the usage requirements in the "build" field of the manifest, e.g.
+/// "build": { "public": { "include_dirs": ["include"], "defines": ["USE_FOO"], "link_libraries": ["m"] } }
+/// Public requirements go on the interface, so packages requiring the headers and the executables
+/// linking the package get them too, private requirements only apply to the library.
+/// Include and link dirs are relative to the package.
+fn add_usage_requirements(dependency_graph: &mut DependencyGraph, library: Ref<DependencyNode>, interface: Ref<DependencyNode>, dir: &str, manifest: &Value) {
+    for (field, node, visibility) in [("public", interface, Visibility::INTERFACE), ("private", library, Visibility::PRIVATE)] {
+        let requirements = &manifest["build"][field];
+        for include_dir in get_strings(&requirements["include_dirs"]) {
+            dependency_graph.add_include_dir(node, &format!("{}/{}", dir, include_dir), visibility);
+        }
+        for definition in get_strings(&requirements["defines"]) {
+            dependency_graph.add_compile_definition(node, &definition, visibility);
+        }
+        for option in get_strings(&requirements["compile_options"]) {
+            dependency_graph.add_compile_option(node, &option, visibility);
+        }
+        for library in get_strings(&requirements["link_libraries"]) {
+            dependency_graph.add_link_library(node, &library, visibility);
+        }
+        for link_dir in get_strings(&requirements["link_dirs"]) {
+            dependency_graph.add_link_dir(node, &format!("{}/{}", dir, link_dir), visibility);
+        }
+        for option in get_strings(&requirements["link_options"]) {
+            dependency_graph.add_link_option(node, &option, visibility);
+        }
+    }
+}
+
+/// Adds the commands in the "generate" field of the manifest, e.g.
+/// "generate": [{ "name": "tables", "command": ["python3", "gen_tables.py"], "inputs": ["gen_tables.py"], "outputs": ["gen/tables.c", "gen/tables.h"] }]
+/// The commands run in the directory of the package and the paths are relative to it.
+/// A command that only knows its outputs when it runs can write them to a "dyndep" file, see
+/// `read_dyndep`.
+/// Returns the custom command nodes and all files they generate.
+fn add_custom_commands(dependency_graph: &mut DependencyGraph, dir: &str, manifest: &Value) -> Result<(Vec<Ref<DependencyNode>>, Vec<String>), String> {
+    let mut custom_commands = vec![];
+    let mut generated_files = vec![];
+    for custom_command in manifest["generate"].as_array().into_iter().flatten() {
+        let Some(name) = custom_command["name"].as_str() else {
+            return Err(format!("Custom command without a name in {}", dir));
+        };
+        let command = get_strings(&custom_command["command"]);
+        if command.is_empty() {
+            return Err(format!("Custom command {} has no command", name));
+        }
+        let outputs: Vec<_> = get_strings(&custom_command["outputs"]).iter().map(|output| format!("{}/{}", dir, output)).collect();
+        if outputs.is_empty() {
+            return Err(format!("Custom command {} has no outputs", name));
+        }
+        let inputs = get_strings(&custom_command["inputs"]).iter().map(|input| format!("{}/{}", dir, input)).collect();
+        let dyndep = custom_command["dyndep"].as_str().map(|dyndep| format!("{}/{}", dir, dyndep));
+
+        generated_files.extend(outputs.iter().cloned());
+        let options = DependencyOptions::CustomCommandOptions {
+            command,
+            working_dir: dir.to_owned(),
+            outputs,
+            dyndep,
+        };
+        custom_commands.push(dependency_graph.add_custom_command(name, inputs, options));
+    }
+
+    return Ok((custom_commands, generated_files));
+}
+
+/// Adds a vendor provided library that we do not build, e.g.
+/// { "name": "vendor", "library": "vendor/lib/libvendor.a", "include_dirs": ["vendor/include"], "link_libraries": ["pthread"] }
+/// Paths are relative to the package.
+fn add_prebuilt_library(dependency_graph: &mut DependencyGraph, dir: &str, prebuilt: &Value) -> Result<Ref<DependencyNode>, String> {
+    let Some(name) = prebuilt["name"].as_str() else {
+        return Err(format!("Prebuilt library without a name in {}", dir));
+    };
+    let Some(library) = prebuilt["library"].as_str() else {
+        return Err(format!("Prebuilt library {} does not specify its library file", name));
+    };
+    let library = format!("{}/{}", dir, library);
+    if !Path::new(&library).is_file() {
+        return Err(format!("Prebuilt library {} does not exist: {}", name, library));
+    }
+
+    let prebuilt_library = dependency_graph.add_imported(name, vec![library.clone()]);
+    for include_dir in get_strings(&prebuilt["include_dirs"]) {
+        dependency_graph.add_include_dir(prebuilt_library, &format!("{}/{}", dir, include_dir), Visibility::INTERFACE);
+    }
+    for link_library in get_strings(&prebuilt["link_libraries"]) {
+        dependency_graph.add_link_library(prebuilt_library, &link_library, Visibility::INTERFACE);
+    }
+    // Shared objects have to be found at run time as well, from wherever the executable is started.
+    if library.ends_with(".so") || library.contains(".so.") {
+        if let Some(library_dir) = fs::canonicalize(&library).ok().and_then(|library| library.parent().map(|dir| dir.to_owned())) {
+            dependency_graph.add_link_option(prebuilt_library, &format!("-Wl,-rpath,{}", library_dir.display()), Visibility::INTERFACE);
+        }
+    }
+
+    return Ok(prebuilt_library);
+}
+
+fn get_strings(value: &Value) -> Vec<String> {
+    return value.as_array().map(|values| {
+        values.iter().filter_map(|value| value.as_str()).map(|value| value.to_owned()).collect()
+    }).unwrap_or_default();
+}
 
+fn get_clib_dependencies(manifest: &Value) -> Vec<String> {
     let mut dependency_names = vec![];
-    let dependencies = &config_file_json["dependencies"];
+    let dependencies = &manifest["dependencies"];
     if !dependencies.is_null() {
         let dependencies = dependencies.as_object().unwrap();
         for (dependency_name, _version) in dependencies {
@@ -0,0 +1,106 @@
+// Finds the chain of jobs that bounded the wall time of a build. Starting at the job that
+// finished last, it follows the job that finished last among the jobs it had to wait for: the
+// compiles of its own target, and all jobs of the targets it depends on.
+#![allow(clippy::needless_return)]
+use std::cmp::{Reverse};
+use std::collections::{HashMap};
+use std::time::{Duration, Instant};
+use crate::build_observer::{BuildObserver, Job};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::work_pool::{WorkResult};
+
+struct JobTiming {
+    target: Ref<DependencyNode>,
+    description: String,
+    is_compile: bool,
+    start: Instant,
+    end: Instant,
+}
+
+/// Prints the critical path of every build, the parallelism achieved and how much each target
+/// added to the critical path.
+pub struct CriticalPathObserver {
+    build_start: Instant,
+    jobs: Vec<JobTiming>,
+}
+
+impl CriticalPathObserver {
+    pub fn new() -> CriticalPathObserver {
+        return CriticalPathObserver {
+            build_start: Instant::now(),
+            jobs: vec![],
+        };
+    }
+
+    /// Returns the indices of the jobs on the critical path, the first job first.
+    fn find_critical_path(&self, graph: &DependencyGraph) -> Vec<usize> {
+        let mut dependencies: HashMap<Ref<DependencyNode>, Vec<Ref<DependencyNode>>> = HashMap::new();
+        let mut critical_path = vec![];
+        let mut current = (0..self.jobs.len()).max_by_key(|i| self.jobs[*i].end);
+        while let Some(i) = current {
+            critical_path.push(i);
+            let job = &self.jobs[i];
+            let target_dependencies = dependencies.entry(job.target).or_insert_with(|| graph.get_all_dependencies(job.target));
+            current = (0..self.jobs.len()).filter(|j| {
+                let other = &self.jobs[*j];
+                let is_own_compile = other.target == job.target && other.is_compile && !job.is_compile;
+                return other.end <= job.start && (is_own_compile || target_dependencies.contains(&other.target));
+            }).max_by_key(|j| self.jobs[*j].end);
+        }
+        critical_path.reverse();
+
+        return critical_path;
+    }
+
+    fn print_report(&self, graph: &DependencyGraph) {
+        let wall_time = self.build_start.elapsed();
+        let critical_path = self.find_critical_path(graph);
+        let critical_time: Duration = critical_path.iter().map(|i| self.jobs[*i].end - self.jobs[*i].start).sum();
+
+        println!("Critical path ({:.3} s of {:.3} s wall time):", critical_time.as_secs_f32(), wall_time.as_secs_f32());
+        let mut target_times: Vec<(Ref<DependencyNode>, Duration)> = vec![];
+        for i in critical_path {
+            let job = &self.jobs[i];
+            let duration = job.end - job.start;
+            println!("  {:>8.3} s  +{:.3} s  {} ({})", (job.start - self.build_start).as_secs_f32(), duration.as_secs_f32(), job.description, graph.get_name(job.target));
+            match target_times.iter_mut().find(|(target, _)| *target == job.target) {
+                Some((_, target_time)) => *target_time += duration,
+                None => target_times.push((job.target, duration)),
+            }
+        }
+
+        // The average number of jobs running at the same time.
+        let job_time: Duration = self.jobs.iter().map(|job| job.end - job.start).sum();
+        println!("Average parallelism: {:.2}", job_time.as_secs_f64() / wall_time.as_secs_f64().max(f64::EPSILON));
+
+        target_times.sort_by_key(|(_, target_time)| Reverse(*target_time));
+        println!("Time added to the critical path per target:");
+        for (target, target_time) in target_times {
+            println!("  {:>8.3} s  {}", target_time.as_secs_f32(), graph.get_name(target));
+        }
+    }
+}
+
+impl BuildObserver for CriticalPathObserver {
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {
+        self.build_start = Instant::now();
+        self.jobs.clear();
+    }
+
+    fn job_finished(&mut self, _graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        // The times of the worker, the scheduler only hears of the job some time later.
+        self.jobs.push(JobTiming {
+            target: job.target,
+            description: job.description.clone(),
+            is_compile: job.source_file.is_some(),
+            start: result.start,
+            end: result.start + result.duration,
+        });
+    }
+
+    fn build_finished(&mut self, graph: &DependencyGraph, _succeeded: bool, _number_of_unbuilt_targets: usize) {
+        if !self.jobs.is_empty() {
+            self.print_report(graph);
+        }
+    }
+}
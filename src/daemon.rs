@@ -0,0 +1,262 @@
+// The daemon keeps the configured graph, the stat cache and the work pool warm between builds.
+// Clients talk to it over a Unix domain socket using JSON messages, one per line.
+#![allow(clippy::needless_return)]
+use std::collections::{VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{parent_id};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use serde_json::{json, Value};
+use crate::build_observer::{LogObserver, ObserverList};
+use crate::configurator::{configure_clib_project, get_clib_source_dirs};
+use crate::profile::{Profile};
+use crate::scheduler::{Scheduler};
+use crate::watcher::{FileWatcher};
+use crate::work_pool::{WorkPool};
+
+enum Request {
+    Build {
+        targets: Vec<String>,
+    },
+    Stop,
+}
+
+struct ClientRequest {
+    // Identifies the client across connections, the thin client connects once per build.
+    client_id: String,
+    request: Request,
+    stream: UnixStream,
+}
+
+/// Queues requests per client and hands them out round robin, so a client sending many requests
+/// cannot starve the others.
+#[derive(Default)]
+struct RequestQueue {
+    clients: VecDeque<(String, VecDeque<ClientRequest>)>,
+}
+
+impl RequestQueue {
+    fn push(&mut self, request: ClientRequest) {
+        match self.clients.iter_mut().find(|(client_id, _)| *client_id == request.client_id) {
+            Some((_, requests)) => requests.push_back(request),
+            None => self.clients.push_back((request.client_id.clone(), VecDeque::from([request]))),
+        }
+    }
+
+    fn pop(&mut self) -> Option<ClientRequest> {
+        let (client_id, mut requests) = self.clients.pop_front()?;
+        let request = requests.pop_front();
+        if !requests.is_empty() {
+            self.clients.push_back((client_id, requests));
+        }
+
+        return request;
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.clients.is_empty();
+    }
+}
+
+pub fn get_socket_path(build_dir: &str) -> String {
+    return format!("{}/beaver.sock", build_dir);
+}
+
+/// Whether a daemon serves the build directory, it builds there whenever a client asks.
+pub fn is_daemon_running(build_dir: &str) -> bool {
+    return UnixStream::connect(get_socket_path(build_dir)).is_ok();
+}
+
+/// Serves build requests until a client asks the daemon to stop.
+pub fn run_daemon(project_dir: &str, build_dir: String, profile: Profile, work_pool: &mut WorkPool) -> bool {
+    let socket_path = get_socket_path(&build_dir);
+    if is_daemon_running(&build_dir) {
+        println!("FATAL: A daemon is already running on {}", socket_path);
+        return false;
+    }
+    let mut graph = match configure_clib_project(project_dir, &mut ObserverList::default()) {
+        Ok(graph) => graph,
+        Err(e) => {
+            println!("FATAL: Could not configure the project: {}", e);
+            return false;
+        },
+    };
+    // The socket file is left behind when a daemon crashed.
+    let _ = fs::remove_file(&socket_path);
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("FATAL: Could not listen on {}: {}", socket_path, e);
+            return false;
+        },
+    };
+
+    let (request_sender, request_receiver) = channel();
+    thread::spawn(move || accept_clients(listener, request_sender));
+
+    let mut scheduler = Scheduler::new(build_dir.clone(), profile, work_pool);
+    let mut watcher = FileWatcher::new();
+    watcher.watch_graph(&graph, get_clib_source_dirs(project_dir));
+    println!("Daemon listening on {}", socket_path);
+
+    let mut request_queue = RequestQueue::default();
+    loop {
+        if request_queue.is_empty() {
+            match request_receiver.recv() {
+                Ok(request) => request_queue.push(request),
+                Err(_) => break,
+            }
+        }
+        while let Ok(request) = request_receiver.try_recv() {
+            request_queue.push(request);
+        }
+        let Some(client_request) = request_queue.pop() else {
+            continue;
+        };
+
+        let mut stream = client_request.stream;
+        match client_request.request {
+            Request::Stop => {
+                println!("Stopping daemon");
+                send_message(&mut stream, json!({ "type": "finished", "succeeded": true }));
+                break;
+            },
+            Request::Build { targets } => {
+                println!("Client {} requested a build of {:?}", client_request.client_id, targets);
+                let Ok(mut output_stream) = stream.try_clone() else {
+                    continue;
+                };
+                let mut observer = ObserverList::for_build(Box::new(LogObserver::new(move |line: &str| {
+                    send_message(&mut output_stream, json!({ "type": "output", "line": line }));
+                })), &build_dir);
+                let change_set = watcher.get_changes();
+                watcher.apply_changes(change_set, project_dir, &mut graph, &mut scheduler, &mut observer);
+
+                let succeeded = match graph.find_targets(&targets) {
+                    Ok(targets) => scheduler.build(&graph, &targets, &mut observer),
+                    Err(e) => {
+                        send_message(&mut stream, json!({ "type": "output", "line": format!("ERROR: {}", e) }));
+                        false
+                    },
+                };
+                send_message(&mut stream, json!({ "type": "finished", "succeeded": succeeded }));
+            },
+        }
+    }
+
+    let _ = fs::remove_file(&socket_path);
+    return true;
+}
+
+/// Asks a running daemon to build the targets and prints its progress.
+/// Returns whether the build succeeded, or None when there is no daemon.
+pub fn build_with_daemon(build_dir: &str, targets: &[String]) -> Option<bool> {
+    let stream = UnixStream::connect(get_socket_path(build_dir)).ok()?;
+    println!("Building with daemon");
+
+    // Builds started from the same shell or editor belong to the same client.
+    let client = format!("parent {}", parent_id());
+    return Some(send_request(stream, json!({ "command": "build", "targets": targets, "client": client })));
+}
+
+/// Asks a running daemon to stop, returns false when there is no daemon.
+pub fn stop_daemon(build_dir: &str) -> bool {
+    let Ok(stream) = UnixStream::connect(get_socket_path(build_dir)) else {
+        println!("No daemon is running");
+        return false;
+    };
+
+    return send_request(stream, json!({ "command": "stop" }));
+}
+
+/// Sends the request and prints the output until the daemon reports that it finished.
+fn send_request(mut stream: UnixStream, request: Value) -> bool {
+    send_message(&mut stream, request);
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        match message["type"].as_str() {
+            Some("output") => {
+                println!("{}", message["line"].as_str().unwrap_or_default());
+            },
+            Some("finished") => {
+                return message["succeeded"].as_bool().unwrap_or(false);
+            },
+            _ => {},
+        }
+    }
+
+    println!("Lost connection to the daemon");
+    return false;
+}
+
+fn send_message(stream: &mut UnixStream, message: Value) {
+    // A client that went away does not need its output anymore.
+    let _ = writeln!(stream, "{}", message);
+}
+
+fn accept_clients(listener: UnixListener, request_sender: Sender<ClientRequest>) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let request_sender = request_sender.clone();
+        thread::spawn(move || read_requests(stream, request_sender));
+    }
+}
+
+/// Returns the user id of the process on the other end of the socket.
+fn get_peer_uid(stream: &UnixStream) -> Option<u32> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut length)
+    };
+    if result != 0 {
+        return None;
+    }
+
+    return Some(credentials.uid);
+}
+
+/// Reads the requests of a single client and forwards them to the build loop.
+fn read_requests(stream: UnixStream, request_sender: Sender<ClientRequest>) {
+    let uid = get_peer_uid(&stream).map_or("unknown".to_owned(), |uid| uid.to_string());
+    let Ok(reader_stream) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(reader_stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        let request = match message["command"].as_str() {
+            Some("build") => {
+                let targets = message["targets"].as_array().map(|targets| {
+                    targets.iter().filter_map(|target| target.as_str()).map(|target| target.to_owned()).collect()
+                }).unwrap_or_default();
+                Request::Build { targets }
+            },
+            Some("stop") => Request::Stop,
+            _ => continue,
+        };
+        // The user is taken from the socket, a client can only choose among its own queues.
+        let client_id = format!("uid {}, {}", uid, message["client"].as_str().unwrap_or("no client id"));
+        let Ok(stream) = stream.try_clone() else {
+            return;
+        };
+        if request_sender.send(ClientRequest { client_id, request, stream }).is_err() {
+            return;
+        }
+    }
+}
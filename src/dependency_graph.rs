@@ -1,22 +1,95 @@
+#![allow(clippy::needless_return)]
 use std::fmt;
+use std::collections::{HashMap};
 use core::fmt::Debug;
-use std::collections::HashMap;
 use std::fmt::Formatter;
-use std::marker::PhantomData;
+use std::path::{Path};
 
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DependencyType {
     INTERFACE,
     LIBRARY,
     EXECUTABLE,
+    // A library that is built outside of the project, e.g. found by pkg-config. Its files are the
+    // prebuilt artifacts, if any, that are linked into the executables depending on it.
+    IMPORTED,
+    // Built like an executable and run by `beaver test`.
+    TEST,
+    // Runs a command that generates files, e.g. sources from protocol definitions. Its files are
+    // the inputs of the command.
+    CUSTOM,
+}
+
+/// Who uses a usage requirement or a requirement on another node, like in CMake.
+/// PRIVATE requirements are used to build the node itself, INTERFACE requirements by the nodes
+/// that require it and PUBLIC requirements by both. The interface requirements of a node required
+/// privately are not passed on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Visibility {
+    PUBLIC,
+    PRIVATE,
+    INTERFACE,
+}
+
+/// The include dirs, definitions and options sources are compiled with, and the libraries, search
+/// paths and options executables are linked with.
+#[derive(Debug, Clone, Default)]
+pub struct UsageRequirements {
+    pub include_dirs: Vec<String>,
+    pub compile_definitions: Vec<String>,
+    pub compile_options: Vec<String>,
+    pub link_libraries: Vec<String>,
+    pub link_dirs: Vec<String>,
+    pub link_options: Vec<String>,
+}
+
+impl UsageRequirements {
+    fn extend(&mut self, other: &UsageRequirements) {
+        extend_unique(&mut self.include_dirs, &other.include_dirs);
+        extend_unique(&mut self.compile_definitions, &other.compile_definitions);
+        extend_unique_options(&mut self.compile_options, &other.compile_options);
+        extend_unique(&mut self.link_libraries, &other.link_libraries);
+        extend_unique(&mut self.link_dirs, &other.link_dirs);
+        extend_unique_options(&mut self.link_options, &other.link_options);
+    }
+
+    /// Only the libraries, search paths and options to link with, without the compile flags.
+    fn get_link_requirements(&self) -> UsageRequirements {
+        return UsageRequirements {
+            link_libraries: self.link_libraries.clone(),
+            link_dirs: self.link_dirs.clone(),
+            link_options: self.link_options.clone(),
+            ..Default::default()
+        };
+    }
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum DependencyOptions {
     ExecutableOptions {
+        // Passed to the driver as is, e.g. "-Wl,--as-needed".
         link_flags: Vec<String>,
         link_libraries: Vec<String>,
+        linker_script: Option<String>,
+    },
+    TestOptions {
+        // The test runs in this directory, usually the directory of the package.
+        working_dir: String,
+        // Overrides the timeout of the test runner.
+        timeout_seconds: Option<u64>,
+    },
+    CustomCommandOptions {
+        // The first element is the program.
+        command: Vec<String>,
+        // The command runs in this directory, usually the directory of the package.
+        working_dir: String,
+        outputs: Vec<String>,
+        // Written by the command, lists the edges that are discovered while building.
+        dyndep: Option<String>,
     },
 }
 
@@ -26,7 +99,11 @@ pub struct DependencyNode {
     dep_type: DependencyType,
     files: Vec<String>,
     options: Option<DependencyOptions>, 
-    requires: Vec<Ref<DependencyNode>>,
+    // Used to build this node.
+    private_requirements: UsageRequirements,
+    // Used to build the nodes requiring this node.
+    interface_requirements: UsageRequirements,
+    requires: Vec<(Ref<DependencyNode>, Visibility)>,
     is_required_by: Vec<Ref<DependencyNode>>,
 }
 
@@ -60,9 +137,12 @@ impl DependencyGraph {
             DependencyType::LIBRARY    => "library:   ",
             DependencyType::INTERFACE  => "interface: ",
             DependencyType::EXECUTABLE => "executable:",
+            DependencyType::IMPORTED   => "imported:  ",
+            DependencyType::CUSTOM     => "custom:    ",
+            DependencyType::TEST       => "test:      ",
         };
         writeln!(f, "{}{} {}", space, dep_type, node.name).unwrap();
-        for dependency in node.requires.iter() {
+        for (dependency, _) in node.requires.iter() {
             self.print_node(*dependency, indent+1, f);
         }
     }
@@ -78,8 +158,10 @@ impl DependencyGraph {
         let node = DependencyNode {
             dep_type: DependencyType::EXECUTABLE,
             name: name.to_owned(),
-            files: files,
+            files,
             options: None,
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
             requires: vec![],
             is_required_by: vec![],
         };
@@ -91,25 +173,91 @@ impl DependencyGraph {
         return node_ref;
     }
 
+    /// Adds an interface, the directories containing the headers become its include dirs.
     pub fn add_interface(&mut self, name: &str, files: Vec<String>) -> Ref<DependencyNode> {
+        let mut include_dirs = vec![];
+        for file in files.iter() {
+            if let Some(include_dir) = Path::new(file).parent().and_then(|parent| parent.to_str()) {
+                extend_unique(&mut include_dirs, &[include_dir.to_owned()]);
+            }
+        }
+
         let node = DependencyNode {
             dep_type: DependencyType::INTERFACE,
             name: name.to_owned(),
-            files: files,
+            files,
             options: None,
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
             requires: vec![],
             is_required_by: vec![],
         };
 
-        return self.add_node(node);
+        let node_ref = self.add_node(node);
+        for include_dir in include_dirs {
+            self.add_include_dir(node_ref, &include_dir, Visibility::INTERFACE);
+        }
+
+        return node_ref;
     }
 
     pub fn add_library(&mut self, name: &str, files: Vec<String>) -> Ref<DependencyNode> {
         let node = DependencyNode {
             dep_type: DependencyType::LIBRARY,
             name: name.to_owned(),
-            files: files,
+            files,
+            options: None,
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
+            requires: vec![],
+            is_required_by: vec![],
+        };
+
+        return self.add_node(node);
+    }
+
+    /// Adds a test, tests are not roots, they are only built when they are run.
+    pub fn add_test(&mut self, name: &str, files: Vec<String>, options: DependencyOptions) -> Ref<DependencyNode> {
+        let node = DependencyNode {
+            dep_type: DependencyType::TEST,
+            name: name.to_owned(),
+            files,
+            options: Some(options),
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
+            requires: vec![],
+            is_required_by: vec![],
+        };
+
+        return self.add_node(node);
+    }
+
+    /// Adds a library that is not built by us, the files are prebuilt archives or shared objects.
+    pub fn add_imported(&mut self, name: &str, files: Vec<String>) -> Ref<DependencyNode> {
+        let node = DependencyNode {
+            dep_type: DependencyType::IMPORTED,
+            name: name.to_owned(),
+            files,
             options: None,
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
+            requires: vec![],
+            is_required_by: vec![],
+        };
+
+        return self.add_node(node);
+    }
+
+    /// Adds a command that generates the outputs in the options from the inputs. Nodes using the
+    /// outputs have to require it, so the command runs before them.
+    pub fn add_custom_command(&mut self, name: &str, inputs: Vec<String>, options: DependencyOptions) -> Ref<DependencyNode> {
+        let node = DependencyNode {
+            dep_type: DependencyType::CUSTOM,
+            name: name.to_owned(),
+            files: inputs,
+            options: Some(options),
+            private_requirements: UsageRequirements::default(),
+            interface_requirements: UsageRequirements::default(),
             requires: vec![],
             is_required_by: vec![],
         };
@@ -120,52 +268,172 @@ impl DependencyGraph {
     /// Adds a requirement relation between origin and requires
     /// Store that origin requires requires
     /// And that requires is required by origin
-    pub fn add_requirement(&mut self, origin: Ref<DependencyNode>, requires: Ref<DependencyNode>) {
-        self.add_requirement_to_node(origin, requires);
+    /// The visibility decides whether the interface requirements of requires are passed on to the
+    /// nodes requiring origin.
+    pub fn add_requirement(&mut self, origin: Ref<DependencyNode>, requires: Ref<DependencyNode>, visibility: Visibility) {
+        self.add_requirement_to_node(origin, requires, visibility);
         self.add_is_required_by_to_node(requires, origin);
     }
 
+    pub fn add_include_dir(&mut self, node_ref: Ref<DependencyNode>, include_dir: &str, visibility: Visibility) {
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            extend_unique(&mut requirements.include_dirs, &[include_dir.to_owned()]);
+        }
+    }
+
+    pub fn add_compile_definition(&mut self, node_ref: Ref<DependencyNode>, definition: &str, visibility: Visibility) {
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            extend_unique(&mut requirements.compile_definitions, &[definition.to_owned()]);
+        }
+    }
+
+    pub fn add_compile_option(&mut self, node_ref: Ref<DependencyNode>, option: &str, visibility: Visibility) {
+        // Not deduplicated, the option may be the argument of the previous one.
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            requirements.compile_options.push(option.to_owned());
+        }
+    }
+
+    pub fn add_link_library(&mut self, node_ref: Ref<DependencyNode>, library: &str, visibility: Visibility) {
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            extend_unique(&mut requirements.link_libraries, &[library.to_owned()]);
+        }
+    }
+
+    pub fn add_link_dir(&mut self, node_ref: Ref<DependencyNode>, link_dir: &str, visibility: Visibility) {
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            extend_unique(&mut requirements.link_dirs, &[link_dir.to_owned()]);
+        }
+    }
+
+    pub fn add_link_option(&mut self, node_ref: Ref<DependencyNode>, option: &str, visibility: Visibility) {
+        // Not deduplicated, the option may be the argument of the previous one.
+        for requirements in self.get_node_mut(node_ref).get_requirements_mut(visibility) {
+            requirements.link_options.push(option.to_owned());
+        }
+    }
+
+    /// The requirements the sources of the node are compiled with: its own private requirements
+    /// and the interface requirements of the nodes it requires privately or publicly.
+    pub fn get_usage_requirements(&self, node_ref: Ref<DependencyNode>) -> UsageRequirements {
+        let node = self.get_node(node_ref);
+        let mut requirements = node.private_requirements.clone();
+        let mut interface_requirements = HashMap::new();
+        for (dependency, visibility) in node.requires.iter() {
+            if *visibility != Visibility::INTERFACE {
+                requirements.extend(self.get_interface_requirements(*dependency, &mut interface_requirements));
+            }
+        }
+
+        return requirements;
+    }
+
+    /// The requirements the node passes on to the nodes requiring it, transitively through the
+    /// nodes it requires publicly or as interface. The objects of a library end up in the
+    /// executables, so they are linked with its private link requirements as well.
+    /// The results are kept in the map, a node reached through many paths is only visited once.
+    fn get_interface_requirements<'a>(&self, node_ref: Ref<DependencyNode>, interface_requirements: &'a mut HashMap<Ref<DependencyNode>, UsageRequirements>) -> &'a UsageRequirements {
+        if !interface_requirements.contains_key(&node_ref) {
+            let node = self.get_node(node_ref);
+            let is_library = node.dep_type == DependencyType::LIBRARY;
+            let mut requirements = node.interface_requirements.clone();
+            if is_library {
+                requirements.extend(&node.private_requirements.get_link_requirements());
+            }
+            for (dependency, visibility) in node.requires.iter() {
+                let dependency_requirements = self.get_interface_requirements(*dependency, interface_requirements);
+                match visibility {
+                    Visibility::PUBLIC | Visibility::INTERFACE => requirements.extend(dependency_requirements),
+                    Visibility::PRIVATE if is_library => requirements.extend(&dependency_requirements.get_link_requirements()),
+                    Visibility::PRIVATE => {},
+                }
+            }
+            interface_requirements.insert(node_ref, requirements);
+        }
+
+        return &interface_requirements[&node_ref];
+    }
+
     pub fn set_executable_options(&mut self, executable_ref: Ref<DependencyNode>, options: DependencyOptions) {
         let executable = self.get_node_mut(executable_ref);
-        if let DependencyOptions::ExecutableOptions {..} = options {
-            executable.options = Some(options);
-        } else {
-            panic!("Trying to set non-executable options {:#?} to an executable {}!", options, executable.name);
+        match options {
+            DependencyOptions::ExecutableOptions {..} => {
+                executable.options = Some(options);
+            },
+            DependencyOptions::TestOptions {..} | DependencyOptions::CustomCommandOptions {..} => {
+                panic!("Only executable options can be set on executable {}", executable.name);
+            },
         }
     }
 
     pub fn get_options(&self, node_ref: Ref<DependencyNode>) -> Option<DependencyOptions> {
         let node = self.get_node(node_ref);
-
-        if let Some(opt) = node.options.as_ref() {
-            Some(opt.clone())
-        } else {
-            None
-        }
+        return node.options.clone();
     }
 
+    /// Finds the interface by its name, the directory of the package can be left out.
     pub fn find_interface(&self, name: &str) -> Option<Ref<DependencyNode>> {
-        for (index, node) in self.arena.iter().enumerate() {
-            if node.dep_type != DependencyType::INTERFACE {
-                continue;
-            }
-            if node.name.ends_with(name) { // Fix this, name is full path spec but dependency name
-                                           // is not.
-                let node_ref: Ref<DependencyNode> = Ref {
-                    idx: index,
-                    _type: std::marker::PhantomData,
-                };
-                return Some(node_ref);
+        return self.get_nodes().into_iter().find(|node_ref| {
+            let node = self.get_node(*node_ref);
+            return node.dep_type == DependencyType::INTERFACE && has_name(&node.name, name);
+        });
+    }
+
+    /// Finds the library by its name, the directory of the package can be left out.
+    pub fn find_library(&self, name: &str) -> Option<Ref<DependencyNode>> {
+        return self.get_nodes().into_iter().find(|node_ref| {
+            let node = self.get_node(*node_ref);
+            return node.dep_type == DependencyType::LIBRARY && has_name(&node.name, name);
+        });
+    }
+
+    pub fn find_node(&self, name: &str) -> Option<Ref<DependencyNode>> {
+        return self.get_nodes().into_iter().find(|node_ref| self.get_node(*node_ref).name == name);
+    }
+
+    /// Looks up the targets by name, all roots are returned when no names are given.
+    pub fn find_targets(&self, names: &[String]) -> Result<Vec<Ref<DependencyNode>>, String> {
+        if names.is_empty() {
+            return Ok(self.get_roots());
+        }
+
+        let mut targets = vec![];
+        for name in names {
+            match self.find_node(name) {
+                Some(target) => targets.push(target),
+                None => return Err(format!("Unknown target: {}", name)),
             }
         }
 
-        return None;
+        return Ok(targets);
+    }
+
+    pub fn get_nodes(&self) -> Vec<Ref<DependencyNode>> {
+        return (0..self.arena.len()).map(|idx| Ref { idx, _type: std::marker::PhantomData }).collect();
     }
 
     pub fn get_roots(&self) -> Vec<Ref<DependencyNode>> {
         return self.roots.clone();
     }
 
+    /// Returns everything the node requires, directly or indirectly.
+    pub fn get_all_dependencies(&self, node_ref: Ref<DependencyNode>) -> Vec<Ref<DependencyNode>> {
+        let mut dependencies = vec![];
+        let mut stack = self.get_dependencies(node_ref);
+        while let Some(dependency) = stack.pop() {
+            if !dependencies.contains(&dependency) {
+                dependencies.push(dependency);
+                stack.extend(self.get_dependencies(dependency));
+            }
+        }
+
+        return dependencies;
+    }
+
+    pub fn get_tests(&self) -> Vec<Ref<DependencyNode>> {
+        return self.get_nodes().into_iter().filter(|node| self.get_type(*node) == DependencyType::TEST).collect();
+    }
+
     pub fn get_name(&self, node: Ref<DependencyNode>) -> String {
         let node = self.get_node(node);
         return node.name.clone();
@@ -173,7 +441,12 @@ impl DependencyGraph {
 
     pub fn get_dependencies(&self, node: Ref<DependencyNode>) -> Vec<Ref<DependencyNode>> {
         let node = self.get_node(node);
-        return node.requires.clone();
+        return node.requires.iter().map(|(dependency, _)| *dependency).collect();
+    }
+
+    pub fn get_dependents(&self, node: Ref<DependencyNode>) -> Vec<Ref<DependencyNode>> {
+        let node = self.get_node(node);
+        return node.is_required_by.clone();
     }
 
     pub fn get_type(&self, node: Ref<DependencyNode>) -> DependencyType {
@@ -206,14 +479,14 @@ impl DependencyGraph {
 
         // Add child to is_required_by. 
         for requiree in is_required_by {
-            self.add_requirement_to_node(requiree, node_ref);
+            self.add_requirement_to_node(requiree, node_ref, Visibility::PUBLIC);
         }
 
         return node_ref;
     }
     
-    fn add_requirement_to_node(&mut self, origin: Ref<DependencyNode>, requirement: Ref<DependencyNode>) {
-        self.arena[origin.idx].requires.push(requirement);
+    fn add_requirement_to_node(&mut self, origin: Ref<DependencyNode>, requirement: Ref<DependencyNode>, visibility: Visibility) {
+        self.arena[origin.idx].requires.push((requirement, visibility));
     }
 
     fn add_is_required_by_to_node(&mut self, origin: Ref<DependencyNode>, is_required_by: Ref<DependencyNode>) {
@@ -222,6 +495,58 @@ impl DependencyGraph {
 }
 
 
+impl DependencyNode {
+    fn get_requirements_mut(&mut self, visibility: Visibility) -> Vec<&mut UsageRequirements> {
+        return match visibility {
+            Visibility::PUBLIC => vec![&mut self.private_requirements, &mut self.interface_requirements],
+            Visibility::PRIVATE => vec![&mut self.private_requirements],
+            Visibility::INTERFACE => vec![&mut self.interface_requirements],
+        };
+    }
+}
+
+/// Whether the node is called name, or name is the last components of its path, so "list_lib"
+/// matches "deps/list_lib" but not "deps/linked-list_lib".
+fn has_name(node_name: &str, name: &str) -> bool {
+    return node_name == name || node_name.ends_with(&format!("/{}", name));
+}
+
+fn extend_unique(items: &mut Vec<String>, new_items: &[String]) {
+    for item in new_items {
+        if !items.contains(item) {
+            items.push(item.clone());
+        }
+    }
+}
+
+// Options whose argument is the next word, e.g. "-isystem /usr/include/foo".
+const OPTIONS_WITH_ARGUMENT: [&str; 10] = ["-isystem", "-idirafter", "-iquote", "-include", "-imacros", "-Xlinker", "-Xpreprocessor", "-Xassembler", "-framework", "-x"];
+
+/// Splits compiler or linker options into the groups of words that belong together.
+fn group_options(options: &[String]) -> Vec<&[String]> {
+    let mut groups = vec![];
+    let mut start = 0;
+    while start < options.len() {
+        let length = if OPTIONS_WITH_ARGUMENT.contains(&options[start].as_str()) && start + 1 < options.len() { 2 } else { 1 };
+        groups.push(&options[start..start + length]);
+        start += length;
+    }
+
+    return groups;
+}
+
+/// Appends the options that are not there yet, an option and its argument count as one, so
+/// "-Xlinker x -Xlinker y" keeps both "-Xlinker".
+pub fn extend_unique_options(options: &mut Vec<String>, new_options: &[String]) {
+    let mut groups: Vec<Vec<String>> = group_options(options).into_iter().map(|group| group.to_vec()).collect();
+    for group in group_options(new_options) {
+        if !groups.iter().any(|existing| existing == group) {
+            options.extend(group.iter().cloned());
+            groups.push(group.to_vec());
+        }
+    }
+}
+
 pub struct Ref<T> {
     idx: usize,
     _type: std::marker::PhantomData<T>,
@@ -246,13 +571,90 @@ impl<T> Debug for Ref<T> {
     }
 }
 
+impl<T> Ref<T> {
+    /// The position of the node in the graph, stable as long as the graph is not reconfigured.
+    pub fn get_index(&self) -> usize {
+        return self.idx;
+    }
+}
+
 impl<T> Clone for Ref<T> {
     fn clone(&self) -> Self {
-        Ref {
-            idx: self.idx,
-            _type: std::marker::PhantomData,
-        }
+        *self
     }
 }
 
 impl<T> Copy for Ref<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_strings(words: &[&str]) -> Vec<String> {
+        return words.iter().map(|word| word.to_string()).collect();
+    }
+
+    #[test]
+    fn extend_unique_options_keeps_options_with_arguments() {
+        let mut options = to_strings(&["-isystem", "/a", "-pthread"]);
+        extend_unique_options(&mut options, &to_strings(&["-isystem", "/b", "-pthread", "-isystem", "/a"]));
+        assert_eq!(options, to_strings(&["-isystem", "/a", "-pthread", "-isystem", "/b"]));
+
+        let mut options = to_strings(&["-Xlinker", "x"]);
+        extend_unique_options(&mut options, &to_strings(&["-Xlinker", "y", "-Xlinker", "x"]));
+        assert_eq!(options, to_strings(&["-Xlinker", "x", "-Xlinker", "y"]));
+    }
+
+    #[test]
+    fn executables_link_with_private_link_requirements_of_libraries() {
+        let mut graph = DependencyGraph::new();
+        let executable = graph.add_executable("app", vec![]);
+        let common_library = graph.add_library("common_lib", vec![]);
+        let library = graph.add_library("list_lib", vec![]);
+        graph.add_requirement(executable, common_library, Visibility::PRIVATE);
+        graph.add_requirement(common_library, library, Visibility::PUBLIC);
+        graph.add_include_dir(library, "list/src", Visibility::PRIVATE);
+        graph.add_link_library(library, "m", Visibility::PRIVATE);
+        graph.add_link_dir(library, "list/lib", Visibility::PRIVATE);
+
+        let requirements = graph.get_usage_requirements(executable);
+        assert_eq!(requirements.link_libraries, vec!["m"]);
+        assert_eq!(requirements.link_dirs, vec!["list/lib"]);
+        assert!(requirements.include_dirs.is_empty());
+    }
+
+    #[test]
+    fn private_requirements_are_not_passed_on() {
+        let mut graph = DependencyGraph::new();
+        let executable = graph.add_executable("app", vec![]);
+        let library = graph.add_library("list_lib", vec![]);
+        let private_library = graph.add_library("parser_lib", vec![]);
+        let interface = graph.add_interface("list_headers", vec![]);
+        graph.add_requirement(executable, library, Visibility::PRIVATE);
+        graph.add_requirement(library, private_library, Visibility::PRIVATE);
+        graph.add_requirement(library, interface, Visibility::INTERFACE);
+        graph.add_compile_definition(library, "LIST", Visibility::PUBLIC);
+        graph.add_include_dir(private_library, "parser/include", Visibility::INTERFACE);
+        graph.add_link_library(private_library, "yaml", Visibility::INTERFACE);
+        graph.add_include_dir(interface, "list/include", Visibility::INTERFACE);
+
+        let requirements = graph.get_usage_requirements(library);
+        assert_eq!(requirements.compile_definitions, vec!["LIST"]);
+        assert_eq!(requirements.include_dirs, vec!["parser/include"]);
+
+        let requirements = graph.get_usage_requirements(executable);
+        assert_eq!(requirements.compile_definitions, vec!["LIST"]);
+        assert_eq!(requirements.include_dirs, vec!["list/include"]);
+        assert_eq!(requirements.link_libraries, vec!["yaml"]);
+    }
+
+    #[test]
+    fn find_library_matches_whole_path_components() {
+        let mut graph = DependencyGraph::new();
+        let linked_list = graph.add_library("deps/linked-list_lib", vec![]);
+        let list = graph.add_library("deps/list_lib", vec![]);
+        assert_eq!(graph.find_library("list_lib"), Some(list));
+        assert_eq!(graph.find_library("linked-list_lib"), Some(linked_list));
+        assert!(graph.find_library("ist_lib").is_none());
+    }
+}
@@ -0,0 +1,253 @@
+// Parses the diagnostics in the output of gcc and clang, e.g.
+// "src/main.c:4:12: warning: unused variable 'x' [-Wunused-variable]".
+#![allow(clippy::needless_return)]
+use std::collections::{BTreeMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use serde_json::{json, Value};
+use crate::build_observer::{BuildObserver, Job};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::filesystem::{join_path};
+use crate::work_pool::{WorkResult};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    // Notes belong to the diagnostic before them.
+    Note,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        return match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+    pub notes: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn to_json(&self) -> Value {
+        return json!({
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+            "severity": self.severity.to_string(),
+            "message": self.message,
+            "notes": self.notes.iter().map(|note| note.to_json()).collect::<Vec<_>>(),
+        });
+    }
+}
+
+/// Returns the diagnostics in the output of the compiler. Lines that are not diagnostics, like
+/// the source excerpts and "In function" lines, are skipped. Notes without a diagnostic to belong
+/// to are dropped.
+pub fn parse_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    for line in output.lines() {
+        let Some(diagnostic) = parse_diagnostic(line) else {
+            continue;
+        };
+        if diagnostic.severity == Severity::Note {
+            if let Some(last) = diagnostics.last_mut() {
+                last.notes.push(diagnostic);
+            }
+        } else {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    return diagnostics;
+}
+
+/// Parses a line of the form "<file>:<line>[:<column>]: <severity>: <message>" from its start, the
+/// message may quote anything, even another diagnostic.
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    // The colon after the drive of a Windows path is part of the file.
+    let drive_length = if is_windows_path(line) { 2 } else { 0 };
+    let file_length = drive_length + line[drive_length..].find(':')?;
+    let (file, rest) = (&line[..file_length], &line[file_length + 1..]);
+    if file.is_empty() {
+        return None;
+    }
+    let (line, rest) = rest.split_once(':')?;
+    let line = line.parse().ok()?;
+    // The column is missing when the diagnostic is about a whole line.
+    let (column, rest) = match rest.split_once(':') {
+        Some((column, rest)) if column.parse::<usize>().is_ok() => (column.parse().ok(), rest),
+        _ => (None, rest),
+    };
+    let severities = [("fatal error", Severity::Error), ("error", Severity::Error), ("warning", Severity::Warning), ("note", Severity::Note)];
+    let (severity, message) = severities.iter().find_map(|(name, severity)| {
+        let message = rest.strip_prefix(' ')?.strip_prefix(name)?.strip_prefix(": ")?;
+        return Some((*severity, message));
+    })?;
+
+    // The same header is named differently from different directories.
+    let file = if is_windows_path(file) { file.to_owned() } else { join_path(".", file) };
+    return Some(Diagnostic {
+        file,
+        line,
+        column,
+        severity,
+        message: message.to_owned(),
+        notes: vec![],
+    });
+}
+
+/// Whether the path starts with a drive, e.g. "C:\src\main.c", such paths are absolute.
+fn is_windows_path(file: &str) -> bool {
+    let mut characters = file.chars();
+    return characters.next().is_some_and(|drive| drive.is_ascii_alphabetic()) && characters.next() == Some(':') && matches!(characters.next(), Some('\\' | '/'));
+}
+
+pub fn get_diagnostics_file(build_dir: &str) -> String {
+    return format!("{}/diagnostics.json", build_dir);
+}
+
+/// Collects the diagnostics of the jobs of a build. A warning in a header is reported by every
+/// translation unit including it, it is only kept once. At the end of the build the diagnostics
+/// are printed grouped by file and written to diagnostics.json in the build directory.
+pub struct DiagnosticsObserver {
+    build_dir: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl DiagnosticsObserver {
+    pub fn new(build_dir: &str) -> DiagnosticsObserver {
+        return DiagnosticsObserver {
+            build_dir: build_dir.to_owned(),
+            diagnostics: vec![],
+        };
+    }
+
+    fn print_summary(&self) {
+        let mut files: BTreeMap<&str, Vec<&Diagnostic>> = BTreeMap::new();
+        for diagnostic in self.diagnostics.iter() {
+            files.entry(&diagnostic.file).or_default().push(diagnostic);
+        }
+
+        println!("Diagnostics:");
+        for (file, diagnostics) in files.iter() {
+            println!("{}", file);
+            for diagnostic in diagnostics {
+                println!("  {}", format_location(diagnostic));
+                for note in diagnostic.notes.iter() {
+                    println!("    {}:{}", note.file, format_location(note));
+                }
+            }
+        }
+        let count = |severity| self.diagnostics.iter().filter(|diagnostic| diagnostic.severity == severity).count();
+        println!("{} errors and {} warnings in {} files", count(Severity::Error), count(Severity::Warning), files.len());
+    }
+}
+
+impl BuildObserver for DiagnosticsObserver {
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {
+        self.diagnostics.clear();
+    }
+
+    fn job_finished(&mut self, _graph: &DependencyGraph, _job: &Job, result: &WorkResult) {
+        let output = match &result.result {
+            Ok(output) => output,
+            Err(output) => output,
+        };
+        for diagnostic in parse_diagnostics(output) {
+            if !self.diagnostics.contains(&diagnostic) {
+                self.diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, _succeeded: bool, _number_of_unbuilt_targets: usize) {
+        if !self.diagnostics.is_empty() {
+            self.print_summary();
+        }
+
+        let diagnostics: Vec<_> = self.diagnostics.iter().map(|diagnostic| diagnostic.to_json()).collect();
+        let diagnostics_file = get_diagnostics_file(&self.build_dir);
+        if let Err(e) = fs::write(&diagnostics_file, serde_json::to_string_pretty(&diagnostics).unwrap()) {
+            println!("ERROR: Could not write {}: {}", diagnostics_file, e);
+        }
+    }
+}
+
+fn format_location(diagnostic: &Diagnostic) -> String {
+    return match diagnostic.column {
+        Some(column) => format!("{}:{}: {}: {}", diagnostic.line, column, diagnostic.severity, diagnostic.message),
+        None => format!("{}: {}: {}", diagnostic.line, diagnostic.severity, diagnostic.message),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_diagnostic_reads_location_and_severity() {
+        let diagnostic = parse_diagnostic("src/main.c:4:12: warning: unused variable 'x' [-Wunused-variable]").unwrap();
+        assert_eq!(diagnostic.file, "./src/main.c");
+        assert_eq!(diagnostic.line, 4);
+        assert_eq!(diagnostic.column, Some(12));
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.message, "unused variable 'x' [-Wunused-variable]");
+
+        let diagnostic = parse_diagnostic("src/list/../main.c:7: fatal error: a.h: No such file or directory").unwrap();
+        assert_eq!(diagnostic.file, "./src/main.c");
+        assert_eq!(diagnostic.line, 7);
+        assert_eq!(diagnostic.column, None);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.message, "a.h: No such file or directory");
+    }
+
+    #[test]
+    fn parse_diagnostic_keeps_windows_paths() {
+        let diagnostic = parse_diagnostic("C:\\src\\main.c:4:12: error: expected ';'").unwrap();
+        assert_eq!(diagnostic.file, "C:\\src\\main.c");
+        assert_eq!((diagnostic.line, diagnostic.column), (4, Some(12)));
+
+        let diagnostic = parse_diagnostic("D:/src/main.c:4: note: declared here").unwrap();
+        assert_eq!(diagnostic.file, "D:/src/main.c");
+        assert_eq!((diagnostic.line, diagnostic.column), (4, None));
+        assert_eq!(diagnostic.severity, Severity::Note);
+    }
+
+    #[test]
+    fn parse_diagnostic_reads_from_the_start_of_the_line() {
+        let diagnostic = parse_diagnostic("main.c:3:1: warning: format \"x: error: %s\" expects an argument").unwrap();
+        assert_eq!(diagnostic.file, "./main.c");
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.message, "format \"x: error: %s\" expects an argument");
+
+        assert!(parse_diagnostic("ld: error: main.o:2: undefined symbol").is_none());
+        assert!(parse_diagnostic(":3:1: error: no file").is_none());
+    }
+
+    #[test]
+    fn parse_diagnostic_skips_other_lines() {
+        assert!(parse_diagnostic("src/main.c: In function 'main':").is_none());
+        assert!(parse_diagnostic("    4 |     int x;").is_none());
+        assert!(parse_diagnostic("main.c:abc: error: not a line").is_none());
+    }
+
+    #[test]
+    fn parse_diagnostics_attaches_notes() {
+        let output = "a.h:1:1: note: orphan\nmain.c:3:5: error: conflicting types for 'f'\na.h:1:5: note: previous declaration of 'f'\n";
+        let diagnostics = parse_diagnostics(output);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].notes.len(), 1);
+        assert_eq!(diagnostics[0].notes[0].file, "./a.h");
+    }
+}
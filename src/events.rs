@@ -0,0 +1,129 @@
+// Writes the progress of a build as JSON lines, one event per line, for IDEs and CI wrappers, e.g.
+// {"event":"job_finished","time":1.25,"target":3,"name":"vec_lib","job":7,"status":"succeeded",...}
+// Every event has the seconds since the stream was opened. Targets are identified by their index
+// in the dependency graph and their name.
+#![allow(clippy::needless_return)]
+use std::fs::{File};
+use std::io::{Write};
+use std::os::fd::{FromRawFd};
+use std::time::{Instant};
+use serde_json::{json, Value};
+use crate::build_observer::{BuildObserver, Job};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::work_pool::{WorkResult};
+
+pub struct EventStream {
+    file: File,
+    start: Instant,
+}
+
+impl EventStream {
+    /// Opens the file to write the events to, or the inherited file descriptor when the
+    /// destination is a number. The descriptor is duplicated, so closing the stream leaves it open.
+    pub fn open(destination: &str) -> Result<EventStream, String> {
+        let file = match destination.parse::<i32>() {
+            // Our own output would be mixed with the events.
+            Ok(fd) if (0..=2).contains(&fd) => return Err(format!("File descriptor {} is stdin, stdout or stderr", fd)),
+            Ok(fd) => {
+                if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                    return Err(format!("File descriptor {} is not open", fd));
+                }
+                let duplicate = unsafe { libc::dup(fd) };
+                if duplicate == -1 {
+                    return Err(format!("Could not duplicate file descriptor {}: {}", fd, std::io::Error::last_os_error()));
+                }
+                unsafe { File::from_raw_fd(duplicate) }
+            },
+            Err(_) => File::create(destination).map_err(|e| format!("Could not create {}: {}", destination, e))?,
+        };
+
+        return Ok(EventStream {
+            file,
+            start: Instant::now(),
+        });
+    }
+
+    /// Writes the event as a single line, so readers never see half an event.
+    fn write(&mut self, event: &str, mut fields: Value) {
+        fields["event"] = json!(event);
+        fields["time"] = json!(self.start.elapsed().as_secs_f64());
+        let line = format!("{}\n", fields);
+        // The build goes on when the reader went away.
+        let _ = self.file.write_all(line.as_bytes());
+    }
+
+    fn write_target_event(&mut self, event: &str, graph: &DependencyGraph, target: Ref<DependencyNode>, mut fields: Value) {
+        fields["target"] = json!(target.get_index());
+        fields["name"] = json!(graph.get_name(target));
+        self.write(event, fields);
+    }
+}
+
+impl BuildObserver for EventStream {
+    fn configure_started(&mut self) {
+        self.write("configure_started", json!({}));
+    }
+
+    fn configure_finished(&mut self, result: Result<&DependencyGraph, &str>) {
+        match result {
+            Ok(graph) => self.write("configure_finished", json!({ "succeeded": true, "number_of_targets": graph.get_nodes().len() })),
+            Err(e) => self.write("configure_finished", json!({ "succeeded": false, "error": e })),
+        }
+    }
+
+    fn build_started(&mut self, graph: &DependencyGraph, targets: &[Ref<DependencyNode>]) {
+        let targets: Vec<_> = targets.iter().map(|target| json!({ "target": target.get_index(), "name": graph.get_name(*target) })).collect();
+        self.write("build_started", json!({ "targets": targets }));
+    }
+
+    fn target_scheduled(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        self.write_target_event("target_scheduled", graph, target, json!({}));
+    }
+
+    fn target_started(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        self.write_target_event("target_started", graph, target, json!({}));
+    }
+
+    fn job_started(&mut self, graph: &DependencyGraph, job: &Job, worker_id: usize) {
+        self.write_target_event("job_started", graph, job.target, json!({
+            "job": job.id,
+            "description": job.description,
+            "reason": job.reason,
+            "command": job.command_line,
+            "worker": worker_id,
+        }));
+    }
+
+    fn job_finished(&mut self, graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        let (status, output) = match &result.result {
+            Ok(output) => ("succeeded", output),
+            Err(output) => ("failed", output),
+        };
+        self.write_target_event("job_finished", graph, job.target, json!({
+            "job": job.id,
+            "status": status,
+            "duration": result.duration.as_secs_f64(),
+            "output": output,
+        }));
+    }
+
+    fn target_failed(&mut self, graph: &DependencyGraph, target: Ref<DependencyNode>) {
+        self.write_target_event("target_failed", graph, target, json!({}));
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, succeeded: bool, number_of_unbuilt_targets: usize) {
+        self.write("build_finished", json!({ "succeeded": succeeded, "number_of_unbuilt_targets": number_of_unbuilt_targets }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_rejects_standard_and_closed_file_descriptors() {
+        for fd in ["0", "1", "2", "100000"] {
+            assert!(EventStream::open(fd).is_err());
+        }
+    }
+}
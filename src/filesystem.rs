@@ -1,6 +1,9 @@
+#![allow(clippy::needless_return)]
 use std::io;
+use std::collections::HashMap;
 use std::fs::{self, DirEntry};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
 
 
 #[derive(Default)]
@@ -17,6 +20,7 @@ impl DirReader {
         return dir_reader;
     }
 
+    #[allow(dead_code)]
     pub fn new_recursive_for(dir: &str) -> DirReader {
         let path = Path::new(dir);
         let mut dir_reader = DirReader::default();
@@ -37,7 +41,7 @@ impl DirReader {
         let filtered = self.files.iter().filter(|file| {
             let end = format!(".{}", extension);
             return file.ends_with(&end);
-        }).map(|file| { file.clone()}).collect();
+        }).cloned().collect();
 
         return filtered;
     }
@@ -57,6 +61,7 @@ impl DirReader {
        return String::from_utf8(content).unwrap();
     }
 
+    #[allow(dead_code)]
     fn read_files_in_dir_recursive(&mut self, dir: &Path) -> io::Result<()> {
         if dir.is_dir() {
             for entry in fs::read_dir(dir)? {
@@ -109,3 +114,187 @@ impl DirReader {
         self.files.push(entry.path().to_str().unwrap().to_owned());
     }
 }
+
+/// Caches the modification times of files, so that checking whether a target is up to date does
+/// not need to stat the same header over and over again.
+#[derive(Default)]
+pub struct StatCache {
+    modified_times: HashMap<String, Option<SystemTime>>,
+}
+
+impl StatCache {
+    pub fn new() -> StatCache {
+        return StatCache::default();
+    }
+
+    /// Returns the modification time of the file, or None if the file does not exist.
+    pub fn get_modified(&mut self, file: &str) -> Option<SystemTime> {
+        let file = normalize_path(file);
+        if let Some(modified) = self.modified_times.get(&file) {
+            return *modified;
+        }
+
+        let modified = fs::metadata(&file).and_then(|metadata| metadata.modified()).ok();
+        self.modified_times.insert(file, modified);
+
+        return modified;
+    }
+
+    /// Forget the cached modification time, the next lookup will stat the file again.
+    pub fn invalidate(&mut self, file: &str) {
+        self.modified_times.remove(&normalize_path(file));
+    }
+
+    pub fn clear(&mut self) {
+        self.modified_times.clear();
+    }
+}
+
+/// Removes "." components, so "./src/main.c" and "src/main.c" refer to the same file.
+pub fn normalize_path(file: &str) -> String {
+    let path: PathBuf = Path::new(file).components().filter(|component| component != &Component::CurDir).collect();
+    return path.to_str().unwrap().to_owned();
+}
+
+/// Reads a makefile style dependency file as written by gcc -MMD.
+/// Returns the prerequisites, or None if the file could not be read.
+pub fn read_depfile(depfile: &str) -> Option<Vec<String>> {
+    let content = fs::read_to_string(depfile).ok()?;
+    return Some(parse_depfile(&content));
+}
+
+/// Returns the prerequisites of the rules in the dependency file. Spaces and '#' in paths are
+/// escaped with a backslash and '$' is doubled, like gcc writes them.
+fn parse_depfile(content: &str) -> Vec<String> {
+    let content = content.replace("\\\n", " ");
+
+    let mut prerequisites = vec![];
+    for line in content.lines() {
+        // Every rule is of the form "target: prerequisite prerequisite ...".
+        let Some((_target, line_prerequisites)) = line.split_once(": ") else {
+            continue;
+        };
+        let mut prerequisite = String::new();
+        let mut characters = line_prerequisites.chars().peekable();
+        while let Some(character) = characters.next() {
+            match character {
+                '\\' if characters.peek().is_some_and(|next| *next == ' ' || *next == '#') => {
+                    prerequisite.push(characters.next().unwrap());
+                },
+                '$' if characters.peek() == Some(&'$') => {
+                    prerequisite.push(characters.next().unwrap());
+                },
+                ' ' => {
+                    if !prerequisite.is_empty() && !prerequisites.contains(&prerequisite) {
+                        prerequisites.push(prerequisite.clone());
+                    }
+                    prerequisite.clear();
+                },
+                c => prerequisite.push(c),
+            }
+        }
+        if !prerequisite.is_empty() && !prerequisites.contains(&prerequisite) {
+            prerequisites.push(prerequisite);
+        }
+    }
+
+    return prerequisites;
+}
+
+/// Joins the relative path to the directory, ".." components are resolved so the result can be
+/// compared with the paths in the graph. Absolute paths replace the directory.
+pub fn join_path(dir: &str, path: &str) -> String {
+    let dir = if path.starts_with('/') { "" } else { dir };
+    let mut components: Vec<_> = dir.split('/').collect();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." if components.last().is_some_and(|last| !last.is_empty() && *last != "." && *last != "..") => {
+                components.pop();
+            },
+            _ => components.push(component),
+        }
+    }
+
+    return components.join("/");
+}
+
+/// Edges that are only known after a command ran, e.g. the headers a generator produced.
+#[derive(Default)]
+pub struct Dyndep {
+    // A file of a target, and the extra files the target produces.
+    pub outputs: Vec<(String, Vec<String>)>,
+    // A file of a target, and the extra files the target needs before it can be built.
+    pub inputs: Vec<(String, Vec<String>)>,
+}
+
+/// Reads a dyndep file with lines of the form "<file> outputs <file> ..." and
+/// "<file> inputs <file> ...", the paths are relative to the directory.
+/// Returns None if the file could not be read or is malformed.
+pub fn read_dyndep(dyndep_file: &str, dir: &str) -> Option<Dyndep> {
+    let content = fs::read_to_string(dyndep_file).ok()?;
+    return parse_dyndep(&content, dir);
+}
+
+fn parse_dyndep(content: &str, dir: &str) -> Option<Dyndep> {
+    let mut dyndep = Dyndep::default();
+    for line in content.lines() {
+        let mut words = line.split_whitespace().map(|word| join_path(dir, word));
+        let Some(file) = words.next() else {
+            continue;
+        };
+        let kind = line.split_whitespace().nth(1)?;
+        let files = words.skip(1).collect();
+        match kind {
+            "outputs" => dyndep.outputs.push((file, files)),
+            "inputs" => dyndep.inputs.push((file, files)),
+            _ => return None,
+        }
+    }
+
+    return Some(dyndep);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_depfile_reads_continuation_lines() {
+        let content = "out/main.c.o: src/main.c src/a.h \\\n  src/b.h\n";
+        assert_eq!(parse_depfile(content), vec!["src/main.c", "src/a.h", "src/b.h"]);
+    }
+
+    #[test]
+    fn parse_depfile_unescapes_paths() {
+        let content = "main.o: my\\ dir/a.h b\\#1.h cost$$.h c:\\\\dir\\\\d.h\n";
+        assert_eq!(parse_depfile(content), vec!["my dir/a.h", "b#1.h", "cost$.h", "c:\\\\dir\\\\d.h"]);
+    }
+
+    #[test]
+    fn parse_depfile_skips_phony_targets_and_duplicates() {
+        // gcc -MP adds a rule without prerequisites for every header.
+        let content = "main.o: main.c a.h a.h\na.h:\n";
+        assert_eq!(parse_depfile(content), vec!["main.c", "a.h"]);
+    }
+
+    #[test]
+    fn parse_dyndep_reads_outputs_and_inputs() {
+        let content = "gen/vec.stamp outputs gen/vec.h\n\nvec.cpp inputs ../list/gen/extra.h /usr/include/a.h\n";
+        let dyndep = parse_dyndep(content, "deps/vec").unwrap();
+        assert_eq!(dyndep.outputs, vec![("deps/vec/gen/vec.stamp".to_owned(), vec!["deps/vec/gen/vec.h".to_owned()])]);
+        assert_eq!(dyndep.inputs, vec![("deps/vec/vec.cpp".to_owned(), vec!["deps/list/gen/extra.h".to_owned(), "/usr/include/a.h".to_owned()])]);
+    }
+
+    #[test]
+    fn parse_dyndep_rejects_malformed_lines() {
+        assert!(parse_dyndep("vec.cpp requires a.h\n", "deps/vec").is_none());
+        assert!(parse_dyndep("vec.cpp\n", "deps/vec").is_none());
+    }
+
+    #[test]
+    fn join_path_keeps_absolute_paths() {
+        assert_eq!(join_path("src", "/usr/include/a.h"), "/usr/include/a.h");
+        assert_eq!(join_path("src/vec", "../list/a.h"), "src/list/a.h");
+    }
+}
@@ -1,3 +1,4 @@
+#![allow(clippy::needless_return)]
 use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
 
 pub trait GraphVisitor {
@@ -0,0 +1,204 @@
+// Installs executables, libraries and public headers under a prefix, and removes them again.
+// DESTDIR is prepended to the prefix, so packagers can stage an installation.
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyType, Ref};
+use crate::work_pool::{WorkPool, WorkInstruction, get_object_file};
+
+/// Where installed executables find the installed shared libraries.
+const INSTALL_RPATH: &str = "-Wl,-rpath,$ORIGIN/../lib";
+
+pub fn get_install_manifest_file(build_dir: &str) -> String {
+    return format!("{}/install_manifest.txt", build_dir);
+}
+
+/// Installs the built targets and the libraries and interfaces they require. The link instructions
+/// of the executables are used to link them again with an rpath relative to the installation.
+/// Writes the installed files to the install manifest.
+pub fn install(graph: &DependencyGraph, targets: &[Ref<DependencyNode>], link_instructions: Vec<WorkInstruction>, build_dir: &str, prefix: &str, work_pool: &mut WorkPool) -> Result<(), String> {
+    let install_dir = format!("{}{}", env::var("DESTDIR").unwrap_or_default(), prefix);
+    let bin_dir = format!("{}/bin", install_dir);
+    let lib_dir = format!("{}/lib", install_dir);
+    let include_dir = format!("{}/include", install_dir);
+
+    let mut nodes = targets.to_vec();
+    for target in targets {
+        for dependency in graph.get_all_dependencies(*target) {
+            if !nodes.contains(&dependency) {
+                nodes.push(dependency);
+            }
+        }
+    }
+
+    // Headers are installed relative to the outermost include dir the nodes are compiled with, the
+    // way other packages include them, e.g. "list/list.h" relative to the deps dir.
+    let mut include_dirs: Vec<String> = vec![];
+    for node in nodes.iter() {
+        for include_dir in graph.get_usage_requirements(*node).include_dirs {
+            if !include_dirs.contains(&include_dir) {
+                include_dirs.push(include_dir);
+            }
+        }
+    }
+    include_dirs.sort_by_key(|include_dir| include_dir.len());
+    // The installed header and the header it was installed from.
+    let mut installed_headers: HashMap<String, String> = HashMap::new();
+
+    // Everything is planned before anything is installed, so a collision leaves nothing behind.
+    let mut instructions = vec![];
+    // The files copied as they are and where they are installed.
+    let mut copies: Vec<(String, String)> = vec![];
+    for link_instruction in link_instructions {
+        let WorkInstruction::Link { language, object_files, link_dirs, link_flags, linker_script, link_libraries, output_file } = link_instruction else {
+            continue;
+        };
+        // The rpaths into the source tree make no sense after installation.
+        let mut link_flags: Vec<_> = link_flags.into_iter().filter(|flag| !flag.starts_with("-Wl,-rpath,")).collect();
+        link_flags.push(INSTALL_RPATH.to_owned());
+        let file_name = Path::new(&output_file).file_name().unwrap().to_str().unwrap().to_owned();
+        let output_file = format!("{}/{}", bin_dir, file_name);
+        instructions.push(WorkInstruction::Link { language, object_files, link_dirs, link_flags, linker_script, link_libraries, output_file });
+    }
+
+    for node in nodes.iter() {
+        match graph.get_type(*node) {
+            DependencyType::LIBRARY => {
+                let object_files = graph.get_files(*node).iter().map(|source| get_object_file(build_dir, source)).collect();
+                let output_file = format!("{}/lib{}.a", lib_dir, get_library_name(&graph.get_name(*node)));
+                instructions.push(WorkInstruction::Archive { object_files, output_file });
+            },
+            DependencyType::IMPORTED => {
+                // Prebuilt shared libraries are needed at run time, archives are linked in already.
+                for library in graph.get_files(*node).iter().filter(|library| library.ends_with(".so") || library.contains(".so.")) {
+                    let file_name = Path::new(library).file_name().unwrap().to_str().unwrap();
+                    copies.push((library.clone(), format!("{}/{}", lib_dir, file_name)));
+                }
+            },
+            DependencyType::INTERFACE => {
+                if !is_exported(graph, targets, *node) {
+                    continue;
+                }
+                for header in graph.get_files(*node) {
+                    let relative_header = include_dirs.iter().find_map(|include_dir| Path::new(&header).strip_prefix(include_dir).ok());
+                    let relative_header = relative_header.unwrap_or(Path::new(&header).file_name().unwrap().as_ref());
+                    let installed_header = format!("{}/{}", include_dir, relative_header.display());
+                    match installed_headers.get(&installed_header) {
+                        Some(other_header) if *other_header == header => continue,
+                        Some(other_header) => return Err(format!("Headers {} and {} would both be installed as {}", other_header, header, installed_header)),
+                        None => {},
+                    }
+                    installed_headers.insert(installed_header.clone(), header.clone());
+                    copies.push((header, installed_header));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut install_succeeded = true;
+    let mut installed_files = vec![];
+    for (source, destination) in copies {
+        match copy_file(&source, &destination) {
+            Ok(()) => installed_files.push(destination),
+            Err(e) => {
+                println!("{}", e);
+                install_succeeded = false;
+            },
+        }
+    }
+    for instruction in instructions {
+        if let Some(output_dir) = Path::new(instruction.get_output_file()).parent() {
+            if let Err(e) = fs::create_dir_all(output_dir) {
+                println!("Could not create {}: {}", output_dir.display(), e);
+                install_succeeded = false;
+                continue;
+            }
+        }
+        println!("{}", instruction.get_description());
+        // Listed even when it fails, a failed link or archive may leave a file behind.
+        installed_files.push(instruction.get_output_file().to_owned());
+        work_pool.schedule_work(instruction);
+    }
+    while let Some(work_result) = work_pool.get_next_result_blocking() {
+        if let Err(e) = work_result.result {
+            println!("{}", e);
+            install_succeeded = false;
+        }
+    }
+
+    // Also written when something failed, so the files that did get installed can be removed.
+    let content: String = installed_files.iter().map(|file| format!("{}\n", file)).collect();
+    let manifest_file = get_install_manifest_file(build_dir);
+    fs::write(&manifest_file, content).map_err(|e| format!("Could not write {}: {}", manifest_file, e))?;
+
+    if !install_succeeded {
+        return Err("Not all files could be installed".to_owned());
+    }
+
+    return Ok(());
+}
+
+/// Whether the headers of the interface are public. They are when a library requires them that is
+/// installed on request or that is required by another library, the headers of a library only
+/// executables use, like the common library of the project, are not.
+fn is_exported(graph: &DependencyGraph, targets: &[Ref<DependencyNode>], interface: Ref<DependencyNode>) -> bool {
+    return graph.get_dependents(interface).iter().any(|library| {
+        if graph.get_type(*library) != DependencyType::LIBRARY {
+            return false;
+        }
+        return targets.contains(library) || graph.get_dependents(*library).iter().any(|dependent| graph.get_type(*dependent) == DependencyType::LIBRARY);
+    });
+}
+
+/// Removes the files listed in the install manifest, and the directories below the include dir
+/// that held the headers once they are empty.
+pub fn uninstall(build_dir: &str) -> Result<(), String> {
+    let manifest_file = get_install_manifest_file(build_dir);
+    let content = fs::read_to_string(&manifest_file).map_err(|e| format!("Could not read {}: {}", manifest_file, e))?;
+    for file in content.lines() {
+        match fs::remove_file(file) {
+            Ok(_) => println!("Removed {}", file),
+            Err(e) => println!("Could not remove {}: {}", file, e),
+        }
+    }
+    for file in content.lines() {
+        remove_empty_header_dirs(file);
+    }
+
+    return fs::remove_file(&manifest_file).map_err(|e| format!("Could not remove {}: {}", manifest_file, e));
+}
+
+/// Removes the directories between the header and the include dir, e.g. "include/list", from
+/// the innermost up to the first that is not empty. Files outside an include dir are skipped.
+fn remove_empty_header_dirs(file: &str) {
+    let dirs: Vec<&Path> = Path::new(file).ancestors().skip(1).collect();
+    let Some(include_dir_index) = dirs.iter().position(|dir| dir.file_name().is_some_and(|name| name == "include")) else {
+        return;
+    };
+    for dir in dirs[..include_dir_index].iter() {
+        if fs::remove_dir(dir).is_err() {
+            return;
+        }
+        println!("Removed {}", dir.display());
+    }
+}
+
+/// Copies the file including its permissions.
+fn copy_file(source: &str, destination: &str) -> Result<(), String> {
+    if let Some(destination_dir) = Path::new(destination).parent() {
+        fs::create_dir_all(destination_dir).map_err(|e| format!("Could not create {}: {}", destination_dir.display(), e))?;
+    }
+    println!("Installing {}", destination);
+    fs::copy(source, destination).map_err(|e| format!("Could not install {}: {}", source, e))?;
+
+    return Ok(());
+}
+
+/// Libraries are named after their directory, e.g. "./data/clib/deps/list_lib" becomes "list".
+fn get_library_name(node_name: &str) -> String {
+    let name = node_name.rsplit('/').next().unwrap_or(node_name);
+    return name.trim_end_matches("_lib").to_owned();
+}
@@ -0,0 +1,243 @@
+// The jobserver protocol of GNU make. A jobserver is a pipe or fifo holding one byte per job that
+// may run besides the one every process may always run. A job takes a byte before it starts and
+// writes it back when it is done, so all processes sharing the jobserver together never run more
+// jobs than make was given with -j.
+#![allow(clippy::needless_return)]
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::{CommandExt};
+use std::process::{Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Permission to run a job, has to be given back when the job is done.
+pub enum JobToken {
+    // The job every process of the jobserver may run without a byte.
+    Implicit,
+    Byte(u8),
+}
+
+pub struct Jobserver {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    // Keeps the fifo of a make we are a client of open.
+    _fifo: Option<File>,
+    // Set when the pipe is ours, it has to be passed to the commands that run sub-makes.
+    makeflags: Option<String>,
+    implicit_token_free: AtomicBool,
+}
+
+impl Jobserver {
+    /// Connects to the jobserver of the make that runs us, None when MAKEFLAGS has none.
+    pub fn connect(makeflags: &str) -> Result<Option<Jobserver>, String> {
+        // Make before 4.2 called the option --jobserver-fds.
+        let Some(auth) = makeflags.split_whitespace().filter_map(|flag| {
+            return flag.strip_prefix("--jobserver-auth=").or_else(|| flag.strip_prefix("--jobserver-fds="));
+        }).next_back() else {
+            return Ok(None);
+        };
+
+        let (read_fd, write_fd, fifo) = match auth.strip_prefix("fifo:") {
+            Some(path) => {
+                // Opened for writing as well, so reading never sees the end of the fifo.
+                let fifo = OpenOptions::new().read(true).write(true).open(path).map_err(|e| format!("Could not open the jobserver fifo {}: {}", path, e))?;
+                (fifo.as_raw_fd(), fifo.as_raw_fd(), Some(fifo))
+            },
+            None => {
+                let fds: Vec<RawFd> = auth.split(',').filter_map(|fd| fd.parse().ok()).collect();
+                let [read_fd, write_fd] = fds[..] else {
+                    return Err(format!("Invalid jobserver: {}", auth));
+                };
+                // Make only passes the pipe to commands it knows to be recursive, e.g. marked with +.
+                for fd in [read_fd, write_fd] {
+                    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                        return Err(format!("The jobserver pipe {} is not open, mark the rule running beaver with +", auth));
+                    }
+                }
+                (read_fd, write_fd, None)
+            },
+        };
+
+        return Ok(Some(Jobserver {
+            read_fd,
+            write_fd,
+            _fifo: fifo,
+            makeflags: None,
+            implicit_token_free: AtomicBool::new(true),
+        }));
+    }
+
+    /// Creates a jobserver for the number of jobs, for the sub-makes of custom commands.
+    pub fn create(number_of_jobs: usize, makeflags: &str) -> Result<Jobserver, String> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(format!("Could not create the jobserver pipe: {}", io::Error::last_os_error()));
+        }
+        // The other flags of make are kept, a jobserver make could not pass to us is replaced.
+        let mut flags: Vec<String> = makeflags.split_whitespace().filter(|flag| !flag.starts_with("-j") && !flag.starts_with("--jobserver-")).map(|flag| flag.to_owned()).collect();
+        flags.push(format!("-j{}", number_of_jobs));
+        flags.push(format!("--jobserver-auth={},{}", fds[0], fds[1]));
+        let jobserver = Jobserver {
+            read_fd: fds[0],
+            write_fd: fds[1],
+            _fifo: None,
+            makeflags: Some(flags.join(" ")),
+            implicit_token_free: AtomicBool::new(true),
+        };
+        for _ in 1..number_of_jobs {
+            jobserver.release(JobToken::Byte(b'+'));
+        }
+
+        return Ok(jobserver);
+    }
+
+    /// Blocks until a job may run.
+    pub fn acquire(&self) -> io::Result<JobToken> {
+        if self.implicit_token_free.swap(false, Ordering::AcqRel) {
+            return Ok(JobToken::Implicit);
+        }
+
+        let mut byte = 0u8;
+        loop {
+            match unsafe { libc::read(self.read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+                1 => return Ok(JobToken::Byte(byte)),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The jobserver was closed")),
+                _ => {
+                    let error = io::Error::last_os_error();
+                    match error.kind() {
+                        io::ErrorKind::Interrupted => {},
+                        // Make may hand out the pipe non-blocking.
+                        io::ErrorKind::WouldBlock => {
+                            let mut poll_fd = libc::pollfd { fd: self.read_fd, events: libc::POLLIN, revents: 0 };
+                            unsafe { libc::poll(&mut poll_fd, 1, -1) };
+                        },
+                        _ => return Err(error),
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn release(&self, token: JobToken) {
+        match token {
+            JobToken::Implicit => self.implicit_token_free.store(true, Ordering::Release),
+            JobToken::Byte(byte) => {
+                // The byte has to go back whatever it takes, or the jobs of make are lost.
+                while unsafe { libc::write(self.write_fd, &byte as *const u8 as *const libc::c_void, 1) } != 1 {
+                    if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+
+    /// Lets a sub-make run by the command use the jobserver. The pipe of a make we are a client of
+    /// is inherited already.
+    pub fn pass_to(&self, command: &mut Command) {
+        let Some(makeflags) = &self.makeflags else {
+            return;
+        };
+        command.env("MAKEFLAGS", makeflags);
+        let fds = [self.read_fd, self.write_fd];
+        unsafe {
+            command.pre_exec(move || {
+                for fd in fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                return Ok(());
+            });
+        }
+    }
+}
+
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        if self.makeflags.is_some() {
+            unsafe {
+                libc::close(self.read_fd);
+                libc::close(self.write_fd);
+            }
+        }
+    }
+}
+
+/// Returns the number of jobs make was given, it is only in MAKEFLAGS with a jobserver.
+/// None when make runs an unlimited number of jobs, i.e. the last -j has no number.
+pub fn get_make_jobs(makeflags: &str) -> Option<usize> {
+    return makeflags.split_whitespace().filter_map(|flag| flag.strip_prefix("-j")).next_back()?.parse().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CString};
+
+    fn create_pipe() -> [RawFd; 2] {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        return fds;
+    }
+
+    fn close_pipe(fds: [RawFd; 2]) {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    /// Takes the implicit token and a byte, and gives them back.
+    fn assert_acquires_byte(jobserver: &Jobserver) {
+        assert!(matches!(jobserver.acquire().unwrap(), JobToken::Implicit));
+        jobserver.release(JobToken::Byte(b'+'));
+        assert!(matches!(jobserver.acquire().unwrap(), JobToken::Byte(b'+')));
+        jobserver.release(JobToken::Implicit);
+    }
+
+    #[test]
+    fn connect_without_jobserver() {
+        assert!(Jobserver::connect("").unwrap().is_none());
+        assert!(Jobserver::connect("ks -j").unwrap().is_none());
+    }
+
+    #[test]
+    fn connect_to_pipe() {
+        for option in ["--jobserver-auth", "--jobserver-fds"] {
+            let fds = create_pipe();
+            let jobserver = Jobserver::connect(&format!(" -j4 {}={},{}", option, fds[0], fds[1])).unwrap().unwrap();
+            assert_acquires_byte(&jobserver);
+            drop(jobserver);
+            close_pipe(fds);
+        }
+    }
+
+    #[test]
+    fn connect_to_fifo() {
+        let path = format!("{}/beaver-jobserver-test-{}", std::env::temp_dir().display(), std::process::id());
+        let c_path = CString::new(path.clone()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let jobserver = Jobserver::connect(&format!("-j4 --jobserver-auth=fifo:{}", path)).unwrap().unwrap();
+        assert_acquires_byte(&jobserver);
+        drop(jobserver);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_rejects_invalid_jobservers() {
+        assert!(Jobserver::connect("-j4 --jobserver-auth=3").is_err());
+        assert!(Jobserver::connect("-j4 --jobserver-auth=fifo:/nonexistent/fifo").is_err());
+        // A pipe make did not pass to us, the tests run in parallel so a closed pipe could be reused.
+        assert!(Jobserver::connect("-j4 --jobserver-auth=100000,100001").is_err());
+        assert!(Jobserver::connect("-j4 --jobserver-auth=-1,-1").is_err());
+    }
+
+    #[test]
+    fn get_make_jobs_reads_the_last_jobs_flag() {
+        assert_eq!(get_make_jobs("-j8 --jobserver-auth=3,4"), Some(8));
+        assert_eq!(get_make_jobs("ks -j2 -j8"), Some(8));
+        assert_eq!(get_make_jobs("-j8 -j"), None);
+        assert_eq!(get_make_jobs("-j"), None);
+        assert_eq!(get_make_jobs("ks --no-print-directory"), None);
+    }
+}
@@ -0,0 +1,59 @@
+// Languages beaver can compile, detected from the extension of the source file.
+#![allow(clippy::needless_return)]
+
+pub const SOURCE_EXTENSIONS: [&str; 6] = ["c", "cc", "cpp", "cxx", "S", "s"];
+pub const HEADER_EXTENSIONS: [&str; 4] = ["h", "hh", "hpp", "hxx"];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Language {
+    C,
+    CXX,
+    // Assembly that is run through the C preprocessor first (.S).
+    AssemblyWithCpp,
+    Assembly,
+}
+
+impl Language {
+    pub fn from_source(source_file: &str) -> Option<Language> {
+        let extension = source_file.rsplit_once('.')?.1;
+        return match extension {
+            "c" => Some(Language::C),
+            "cc" | "cpp" | "cxx" => Some(Language::CXX),
+            "S" => Some(Language::AssemblyWithCpp),
+            "s" => Some(Language::Assembly),
+            _ => None,
+        };
+    }
+
+    /// The driver used to compile and link this language.
+    pub fn get_driver(&self) -> &'static str {
+        return match self {
+            Language::C | Language::AssemblyWithCpp | Language::Assembly => "/usr/bin/gcc",
+            Language::CXX => "/usr/bin/g++",
+        };
+    }
+
+    /// C sources keep the default standard of the compiler, existing projects rely on it.
+    pub fn get_standard_flag(&self) -> Option<&'static str> {
+        return match self {
+            Language::CXX => Some("-std=c++17"),
+            Language::C | Language::AssemblyWithCpp | Language::Assembly => None,
+        };
+    }
+
+    /// Only preprocessed sources use include dirs and defines, and have header dependencies.
+    pub fn uses_preprocessor(&self) -> bool {
+        return *self != Language::Assembly;
+    }
+}
+
+/// Objects are named after their source, e.g. "main.cpp.o". When one of them is C++ the C++ driver
+/// has to link, so the C++ runtime is linked in.
+pub fn get_link_language(object_files: &[String]) -> Language {
+    let has_cxx_objects = object_files.iter().any(|object_file| {
+        return Language::from_source(object_file.trim_end_matches(".o")) == Some(Language::CXX);
+    });
+
+    return if has_cxx_objects { Language::CXX } else { Language::C };
+}
@@ -1,61 +1,475 @@
+mod build_log;
+mod build_observer;
+mod command_line;
+mod compile_commands;
 mod configurator;
+mod critical_path;
+mod daemon;
 mod dependency_graph;
+mod diagnostics;
+mod events;
 mod filesystem;
 mod scheduler;
+mod simulate;
+mod test_runner;
+mod throttle;
+mod trace;
 mod work_pool;
 mod graph_walker;
+mod install;
+mod jobserver;
+mod language;
+mod pkg_config;
+mod profile;
 mod builder;
+mod watcher;
 
 use std::env;
 use std::fs;
-use std::io::{ErrorKind};
-use std::time::{Instant};
-use builder::{Builder}; 
-use configurator::{configure_clib_project};
+use std::io::{ErrorKind, Write};
+use std::os::unix::process::{CommandExt};
+use std::path::{Path};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use build_log::{print_stats, read_job_durations, read_peak_rss};
+use build_observer::{BuildObserver, ObserverList, PlanObserver, ProgressObserver};
+use builder::{Builder};
+use command_line::{parse_arguments, Arguments, OutputFormat, Subcommand};
+use compile_commands::{get_compile_commands_file, write_compile_commands};
+use configurator::{configure_clib_project, get_clib_source_dirs, read_project_manifest};
+use daemon::{build_with_daemon, is_daemon_running, run_daemon, stop_daemon};
+use dependency_graph::{DependencyGraph, DependencyOptions, DependencyType};
+use events::{EventStream};
 use graph_walker::{GraphWalker, GraphVisitor};
+use install::{install, uninstall};
+use jobserver::{Jobserver, get_make_jobs};
+use profile::{Profile};
 use scheduler::{Scheduler};
+use simulate::{Simulation, SchedulingPolicy};
+use test_runner::{TestStatus, matches_filter, run_tests, write_junit_xml};
+use throttle::{ResourceLimits};
+use trace::{TraceObserver};
+use watcher::{FileWatcher};
 use work_pool::{WorkPool};
 
+const PROJECT_DIRECTORY: &str = "./data/clib";
+
 fn main() {
-    println!("Beavers will start building!");
+    let arguments = match parse_arguments(env::args().skip(1).collect()) {
+        Ok(arguments) => arguments,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            process::exit(2);
+        },
+    };
+    // Output meant for other programs must not be mixed with our progress.
+    let verbose = arguments.format == OutputFormat::Text;
+    if verbose {
+        println!("Beavers will start building!");
+    }
+
+    let profile = match read_project_manifest(PROJECT_DIRECTORY).and_then(|manifest| Profile::find(&arguments.profile, &manifest)) {
+        Ok(profile) => profile,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            process::exit(2);
+        },
+    };
 
     let mut build_directory = env::current_dir().unwrap();
-    build_directory.push("beaver_build_debug");
+    build_directory.push(profile.get_build_dir_name());
 
-    println!("Build directory: {}", build_directory.display());
+    if verbose {
+        println!("Build directory: {}", build_directory.display());
+    }
     match fs::create_dir(build_directory.clone()) {
-        Ok(_) => {
+        Ok(_) if verbose => {
             println!("Created build directory");
         },
-        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists && verbose => {
             println!("Build directory already exists");
-        }, 
+        },
+        Ok(_) => {},
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {},
         Err(e) => {
             println!("FATAL: Could not create build directory {}", e);
             return;
         },
     }
+    let build_directory = build_directory.to_str().unwrap().to_owned();
+
+    // A running daemon already has the graph configured, let it do the work. It builds in the same
+    // build directory, so nothing else may build there while it runs.
+    let builds = match arguments.subcommand {
+        Subcommand::Build => !arguments.dry_run,
+        Subcommand::Watch | Subcommand::Test | Subcommand::Run | Subcommand::Install => true,
+        _ => false,
+    };
+    if builds && is_daemon_running(&build_directory) {
+        if arguments.subcommand != Subcommand::Build || arguments.sequential || arguments.events.is_some() || arguments.trace.is_some() {
+            println!("FATAL: A daemon is building in {}, stop it with \"beaver_build daemon --stop\" first", build_directory);
+            process::exit(1);
+        }
+        if arguments.jobs.is_some() || arguments.max_load.is_some() || arguments.memory_budget.is_some() {
+            println!("FATAL: The daemon runs jobs with the limits it was started with, -j, -l and --memory-budget cannot be passed to it");
+            process::exit(2);
+        }
+        if let Some(build_succeeded) = build_with_daemon(&build_directory, &arguments.targets) {
+            process::exit(if build_succeeded { 0 } else { 1 });
+        }
+    }
+    if arguments.subcommand == Subcommand::Stats {
+        if let Err(e) = print_stats(&build_directory) {
+            println!("FATAL: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if arguments.subcommand == Subcommand::Uninstall {
+        if let Err(e) = uninstall(&build_directory) {
+            println!("FATAL: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if arguments.subcommand == Subcommand::Daemon && arguments.stop {
+        process::exit(if stop_daemon(&build_directory) { 0 } else { 1 });
+    }
+
+    let mut work_pool = create_work_pool(&arguments, &build_directory);
+    if arguments.subcommand == Subcommand::Daemon {
+        process::exit(if run_daemon(PROJECT_DIRECTORY, build_directory, profile, &mut work_pool) { 0 } else { 1 });
+    }
+
+    let mut observer = ObserverList::for_build(Box::new(ProgressObserver::new()), &build_directory);
+    if let Some(destination) = &arguments.events {
+        match EventStream::open(destination) {
+            Ok(event_stream) => observer.add(Box::new(event_stream)),
+            Err(e) => {
+                println!("FATAL: {}", e);
+                process::exit(2);
+            },
+        }
+    }
+    if let Some(trace_file) = &arguments.trace {
+        observer.add(Box::new(TraceObserver::new(trace_file)));
+    }
+
+    observer.configure_started();
+    let configure_result = configure_clib_project(PROJECT_DIRECTORY, &mut observer);
+    observer.configure_finished(configure_result.as_ref().map_err(|e| e.as_str()));
+    let mut dependency_graph = match configure_result {
+        Ok(dependency_graph) => dependency_graph,
+        Err(e) => {
+            println!("FATAL: Could not configure the project: {}", e);
+            process::exit(1);
+        },
+    };
+    if verbose {
+        println!("Graph: {}", dependency_graph);
+    }
 
-    let mut dependency_graph = configure_clib_project("./data/clib");
-    println!("Graph: {}", dependency_graph);
+    let build_succeeded = match arguments.subcommand {
+        Subcommand::Build => build(&arguments, &mut dependency_graph, build_directory, profile, &mut work_pool, &mut observer),
+        Subcommand::Watch => watch(&arguments, dependency_graph, build_directory, profile, &mut work_pool, &mut observer),
+        Subcommand::Test => test(&arguments, &dependency_graph, build_directory, profile, &mut work_pool, &mut observer),
+        Subcommand::Run => run(&arguments, &dependency_graph, build_directory, profile, &mut work_pool, &mut observer),
+        Subcommand::Install => install_targets(&arguments, &dependency_graph, build_directory, profile, &mut work_pool, &mut observer),
+        Subcommand::CompileCommands => {
+            match write_compile_commands(&dependency_graph, &profile, &build_directory) {
+                Ok(_) => {
+                    println!("Wrote {}", get_compile_commands_file(&build_directory));
+                    true
+                },
+                Err(e) => {
+                    println!("FATAL: {}", e);
+                    false
+                },
+            }
+        },
+        Subcommand::Simulate => simulate(&arguments, &dependency_graph, &build_directory),
+        Subcommand::Uninstall | Subcommand::Stats => unreachable!(),
+        Subcommand::Daemon => unreachable!(),
+    };
 
-    let mut work_pool = WorkPool::new(4);
-    
-    // Build all targets sequentially, uncomment to enable
-    //let roots = dependency_graph.get_roots();
-    //let mut builder = Builder::new(build_directory.to_str().unwrap().to_owned(), &mut work_pool);
-    //let mut graph_walker = GraphWalker::new(&mut dependency_graph);
-    //// Build all top levels (executables)
-    //for root in roots {
-    //    graph_walker.walk(root, &mut builder as &mut dyn GraphVisitor);
-    //    builder.reset();
-    //}
+    if !build_succeeded {
+        process::exit(1);
+    }
+}
+
+/// Joins the jobserver of make when run by make, or else becomes the jobserver of the sub-makes
+/// custom commands run.
+#[allow(clippy::needless_return)]
+fn create_work_pool(arguments: &Arguments, build_directory: &str) -> WorkPool {
+    let makeflags = env::var("MAKEFLAGS").unwrap_or_default();
+    let mut jobserver = match Jobserver::connect(&makeflags) {
+        Ok(jobserver) => jobserver,
+        Err(e) => {
+            println!("ERROR: {}, not sharing the jobs of make", e);
+            None
+        },
+    };
+    let number_of_workers = arguments.jobs
+        .or_else(|| jobserver.as_ref().and_then(|_| get_make_jobs(&makeflags)))
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |parallelism| parallelism.get()));
+    if jobserver.is_none() {
+        jobserver = match Jobserver::create(number_of_workers, &makeflags) {
+            Ok(jobserver) => Some(jobserver),
+            Err(e) => {
+                println!("ERROR: {}", e);
+                None
+            },
+        };
+    }
+
+    let mut limits = ResourceLimits {
+        max_load: arguments.max_load,
+        memory_budget: arguments.memory_budget.map(|memory_budget| memory_budget * 1024),
+        peak_rss: Default::default(),
+    };
+    // The memory a job needs is estimated from its last build, a first build only keeps to the slots.
+    if limits.memory_budget.is_some() {
+        limits.peak_rss = read_peak_rss(build_directory).unwrap_or_default();
+    }
+    return WorkPool::new(number_of_workers, jobserver, limits);
+}
+
+#[allow(clippy::needless_return)]
+fn build(arguments: &Arguments, dependency_graph: &mut DependencyGraph, build_directory: String, profile: Profile, work_pool: &mut WorkPool, observer: &mut dyn BuildObserver) -> bool {
+    let targets = match dependency_graph.find_targets(&arguments.targets) {
+        Ok(targets) => targets,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            return false;
+        },
+    };
+
+    if arguments.dry_run {
+        let mut scheduler = Scheduler::new(build_directory, profile, work_pool);
+        scheduler.set_dry_run(true);
+        let mut plan_observer = PlanObserver::new(arguments.format == OutputFormat::Json);
+        return scheduler.build(dependency_graph, &targets, &mut plan_observer);
+    }
 
-    // Scheduler builds all targets parallel, depending on dependency
     let start = Instant::now();
-    let mut scheduler = Scheduler::new();
-    scheduler.build_all(&dependency_graph);
+    let build_succeeded = if arguments.sequential {
+        // Build all targets sequentially
+        if let Err(e) = write_compile_commands(dependency_graph, &profile, &build_directory) {
+            println!("ERROR: {}", e);
+        }
+        let mut builder = Builder::new(build_directory, profile, work_pool);
+        let mut graph_walker = GraphWalker::new(dependency_graph);
+        for target in targets {
+            graph_walker.walk(target, &mut builder as &mut dyn GraphVisitor);
+            builder.reset();
+        }
+        !builder.has_failed()
+    } else {
+        // Scheduler builds all targets parallel, depending on dependency
+        let mut scheduler = Scheduler::new(build_directory.clone(), profile, work_pool);
+        scheduler.build(dependency_graph, &targets, observer)
+    };
     let duration = start.elapsed();
 
     println!("Build time is: {} s", duration.as_secs_f32());
+
+    return build_succeeded;
+}
+
+/// Builds the tests and runs them, returns whether all tests passed.
+#[allow(clippy::needless_return)]
+fn test(arguments: &Arguments, dependency_graph: &DependencyGraph, build_directory: String, profile: Profile, work_pool: &mut WorkPool, observer: &mut dyn BuildObserver) -> bool {
+    let tests = if arguments.targets.is_empty() {
+        dependency_graph.get_tests()
+    } else {
+        match dependency_graph.find_targets(&arguments.targets) {
+            Ok(tests) => tests,
+            Err(e) => {
+                println!("FATAL: {}", e);
+                return false;
+            },
+        }
+    };
+    let tests: Vec<_> = tests.into_iter().filter(|test| {
+        return arguments.filter.as_ref().is_none_or(|filter| matches_filter(&dependency_graph.get_name(*test), filter));
+    }).collect();
+    if tests.is_empty() {
+        println!("No tests to run");
+        return true;
+    }
+
+    let mut scheduler = Scheduler::new(build_directory.clone(), profile, work_pool);
+    if !scheduler.build(dependency_graph, &tests, observer) {
+        println!("Could not build the tests");
+        return false;
+    }
+
+    let results = run_tests(dependency_graph, &tests, &build_directory, arguments.timeout.map(Duration::from_secs), work_pool);
+    for result in results.iter() {
+        let status = match result.status {
+            TestStatus::Passed => "PASS",
+            TestStatus::Failed => "FAIL",
+            TestStatus::TimedOut => "TIMEOUT",
+        };
+        println!("{:<7} {} ({:.2} s)", status, result.name, result.duration.as_secs_f32());
+        if result.status != TestStatus::Passed {
+            println!("{}", result.output.trim_end());
+        }
+    }
+    let count = |status| results.iter().filter(|result| result.status == status).count();
+    println!("{} tests, {} passed, {} failed, {} timed out", results.len(), count(TestStatus::Passed), count(TestStatus::Failed), count(TestStatus::TimedOut));
+
+    if let Some(junit) = &arguments.junit {
+        if let Err(e) = write_junit_xml(junit, &results) {
+            println!("ERROR: {}", e);
+            return false;
+        }
+    }
+
+    return count(TestStatus::Passed) == results.len();
+}
+
+/// Builds the targets and installs them under the prefix.
+#[allow(clippy::needless_return)]
+fn install_targets(arguments: &Arguments, dependency_graph: &DependencyGraph, build_directory: String, profile: Profile, work_pool: &mut WorkPool, observer: &mut dyn BuildObserver) -> bool {
+    let targets = match dependency_graph.find_targets(&arguments.targets) {
+        Ok(targets) => targets,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            return false;
+        },
+    };
+
+    let mut scheduler = Scheduler::new(build_directory.clone(), profile, work_pool);
+    if !scheduler.build(dependency_graph, &targets, observer) {
+        return false;
+    }
+    let link_instructions = targets.iter().filter_map(|target| scheduler.get_built_link_instruction(dependency_graph, *target)).collect();
+
+    return match install(dependency_graph, &targets, link_instructions, &build_directory, &arguments.prefix, work_pool) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            false
+        },
+    };
+}
+
+/// Replays the jobs of the build log with every number of workers and scheduling policy, and
+/// prints how long the build would take.
+#[allow(clippy::needless_return)]
+fn simulate(arguments: &Arguments, dependency_graph: &DependencyGraph, build_directory: &str) -> bool {
+    let targets = match dependency_graph.find_targets(&arguments.targets) {
+        Ok(targets) => targets,
+        Err(e) => {
+            println!("FATAL: {}", e);
+            return false;
+        },
+    };
+    let durations = match read_job_durations(build_directory) {
+        Ok(durations) => durations,
+        Err(e) => {
+            println!("FATAL: {}, build the project first", e);
+            return false;
+        },
+    };
+
+    let simulation = Simulation::new(dependency_graph, &targets, build_directory, &durations);
+    println!("Simulating {} jobs, {} of them not in the build log", simulation.get_number_of_jobs(), simulation.number_of_estimated_jobs);
+    println!("{:>5}  {:<14} {:>10}  {:>11}", "Jobs", "Policy", "Wall time", "Utilization");
+    for job_count in arguments.job_counts.iter() {
+        for (policy, name) in [(SchedulingPolicy::Fifo, "fifo"), (SchedulingPolicy::CriticalPath, "critical-path")] {
+            let result = simulation.run(dependency_graph, *job_count, policy);
+            println!("{:>5}  {:<14} {:>8.3} s  {:>10.0}%", job_count, name, result.wall_time, result.utilization * 100.0);
+        }
+    }
+
+    return true;
+}
+
+/// Builds the executable and replaces beaver with it, so its exit status becomes ours.
+/// Only returns when the executable could not be built or started.
+#[allow(clippy::needless_return)]
+fn run(arguments: &Arguments, dependency_graph: &DependencyGraph, build_directory: String, profile: Profile, work_pool: &mut WorkPool, observer: &mut dyn BuildObserver) -> bool {
+    let target = match dependency_graph.find_targets(&arguments.targets) {
+        Ok(targets) => targets[0],
+        Err(e) => {
+            println!("FATAL: {}", e);
+            return false;
+        },
+    };
+    if !matches!(dependency_graph.get_type(target), DependencyType::EXECUTABLE | DependencyType::TEST) {
+        println!("FATAL: {} is not an executable", dependency_graph.get_name(target));
+        return false;
+    }
+
+    let mut scheduler = Scheduler::new(build_directory.clone(), profile, work_pool);
+    if !scheduler.build(dependency_graph, &[target], observer) {
+        return false;
+    }
+
+    // Prebuilt shared libraries have to be found by the dynamic linker.
+    let mut library_dirs = dependency_graph.get_usage_requirements(target).link_dirs;
+    for dependency in dependency_graph.get_all_dependencies(target) {
+        if dependency_graph.get_type(dependency) != DependencyType::IMPORTED {
+            continue;
+        }
+        for library in dependency_graph.get_files(dependency) {
+            let Some(library_dir) = Path::new(&library).parent().and_then(|dir| fs::canonicalize(dir).ok()) else {
+                continue;
+            };
+            let library_dir = library_dir.display().to_string();
+            if !library_dirs.contains(&library_dir) {
+                library_dirs.push(library_dir);
+            }
+        }
+    }
+
+    // Like the files it opens, the executable runs in its package: tests in the directory of
+    // their dependency, like `beaver_build test` runs them, the other executables in the project.
+    let working_dir = match dependency_graph.get_options(target) {
+        Some(DependencyOptions::TestOptions { working_dir, .. }) => working_dir,
+        _ => PROJECT_DIRECTORY.to_owned(),
+    };
+    let executable = format!("{}/{}", build_directory, dependency_graph.get_name(target));
+    println!("Running {}", executable);
+    let _ = std::io::stdout().flush();
+    let mut command = process::Command::new(&executable);
+    command.args(&arguments.run_arguments).current_dir(&working_dir);
+    if !library_dirs.is_empty() {
+        if let Ok(library_path) = env::var("LD_LIBRARY_PATH") {
+            library_dirs.push(library_path);
+        }
+        command.env("LD_LIBRARY_PATH", library_dirs.join(":"));
+    }
+    let e = command.exec();
+    println!("FATAL: Could not run {}: {}", executable, e);
+
+    return false;
+}
+
+/// Keeps the graph and the build state in memory and rebuilds whenever a file changes.
+fn watch(arguments: &Arguments, mut dependency_graph: DependencyGraph, build_directory: String, profile: Profile, work_pool: &mut WorkPool, observer: &mut dyn BuildObserver) -> bool {
+    let mut scheduler = Scheduler::new(build_directory.clone(), profile, work_pool);
+    let mut watcher = FileWatcher::new();
+    watcher.watch_graph(&dependency_graph, get_clib_source_dirs(PROJECT_DIRECTORY));
+
+    loop {
+        match dependency_graph.find_targets(&arguments.targets) {
+            Ok(targets) => {
+                let start = Instant::now();
+                let build_succeeded = scheduler.build(&dependency_graph, &targets, observer);
+                let duration = start.elapsed();
+                println!("Build {} in {} s", if build_succeeded { "succeeded" } else { "failed" }, duration.as_secs_f32());
+            },
+            Err(e) => {
+                println!("ERROR: {}", e);
+            },
+        }
+        println!("Watching for changes...");
+
+        let change_set = watcher.wait_for_changes(Duration::from_millis(100));
+        watcher.apply_changes(change_set, PROJECT_DIRECTORY, &mut dependency_graph, &mut scheduler, observer);
+    }
 }
@@ -0,0 +1,53 @@
+// Resolves system libraries with pkg-config into imported library nodes.
+#![allow(clippy::needless_return)]
+use std::process::{Command};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyType, Ref, Visibility};
+
+/// Returns the imported library node of the pkg-config package, the node is created the first time
+/// the package is required. Its cflags and libs become the usage requirements of the node.
+pub fn find_system_dependency(graph: &mut DependencyGraph, package: &str) -> Result<Ref<DependencyNode>, String> {
+    if let Some(node) = graph.find_node(package) {
+        if graph.get_type(node) == DependencyType::IMPORTED {
+            return Ok(node);
+        }
+    }
+
+    let cflags = run_pkg_config(package, "--cflags")?;
+    let libs = run_pkg_config(package, "--libs")?;
+
+    let imported = graph.add_imported(package, vec![]);
+    for flag in cflags {
+        if let Some(include_dir) = flag.strip_prefix("-I") {
+            graph.add_include_dir(imported, include_dir, Visibility::INTERFACE);
+        } else if let Some(definition) = flag.strip_prefix("-D") {
+            graph.add_compile_definition(imported, definition, Visibility::INTERFACE);
+        } else {
+            graph.add_compile_option(imported, &flag, Visibility::INTERFACE);
+        }
+    }
+    for flag in libs {
+        if let Some(library) = flag.strip_prefix("-l") {
+            graph.add_link_library(imported, library, Visibility::INTERFACE);
+        } else if let Some(link_dir) = flag.strip_prefix("-L") {
+            graph.add_link_dir(imported, link_dir, Visibility::INTERFACE);
+        } else {
+            graph.add_link_option(imported, &flag, Visibility::INTERFACE);
+        }
+    }
+
+    return Ok(imported);
+}
+
+fn run_pkg_config(package: &str, option: &str) -> Result<Vec<String>, String> {
+    let output = match Command::new("pkg-config").arg(option).arg(package).output() {
+        Ok(output) => output,
+        Err(e) => return Err(format!("Could not run pkg-config for system dependency {}: {}", package, e)),
+    };
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("System dependency {} was not found by pkg-config: {}", package, error.trim()));
+    }
+
+    let flags = String::from_utf8_lossy(&output.stdout);
+    return Ok(flags.split_whitespace().map(|flag| flag.to_owned()).collect());
+}
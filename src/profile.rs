@@ -0,0 +1,97 @@
+// Build profiles select optimization, debug info, defines and warnings.
+// Custom profiles can be defined in the "profiles" field of the project manifest, e.g.
+// "profiles": { "size": { "inherits": "release", "optimization": "s" } }
+#![allow(clippy::needless_return)]
+use serde_json::{Value};
+use crate::dependency_graph::{UsageRequirements};
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    // Passed as -O<optimization>, e.g. "0", "2" or "s".
+    pub optimization: String,
+    pub debug_info: bool,
+    pub defines: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Profile {
+    pub fn get_builtin(name: &str) -> Option<Profile> {
+        let (optimization, debug_info, defines, warnings) = match name {
+            "debug" => ("0", true, vec![], vec!["-Wall"]),
+            "release" => ("2", false, vec!["NDEBUG"], vec![]),
+            "relwithdebinfo" => ("2", true, vec!["NDEBUG"], vec![]),
+            _ => return None,
+        };
+
+        return Some(Profile {
+            name: name.to_owned(),
+            optimization: optimization.to_owned(),
+            debug_info,
+            defines: defines.into_iter().map(|define| define.to_owned()).collect(),
+            warnings: warnings.into_iter().map(|warning| warning.to_owned()).collect(),
+        });
+    }
+
+    /// Looks up the profile in the manifest first, so builtin profiles can be overridden.
+    pub fn find(name: &str, manifest: &Value) -> Result<Profile, String> {
+        return Profile::find_recursive(name, manifest, &mut vec![]);
+    }
+
+    fn find_recursive(name: &str, manifest: &Value, visited: &mut Vec<String>) -> Result<Profile, String> {
+        let definition = &manifest["profiles"][name];
+        if definition.is_null() || visited.iter().any(|visited_name| visited_name == name) {
+            return Profile::get_builtin(name).ok_or(format!("Unknown profile: {}", name));
+        }
+        visited.push(name.to_owned());
+
+        let base_name = definition["inherits"].as_str().unwrap_or("debug");
+        let mut profile = Profile::find_recursive(base_name, manifest, visited)?;
+        profile.name = name.to_owned();
+        if let Some(optimization) = definition["optimization"].as_str() {
+            profile.optimization = optimization.to_owned();
+        }
+        if let Some(debug_info) = definition["debug_info"].as_bool() {
+            profile.debug_info = debug_info;
+        }
+        if let Some(defines) = get_strings(&definition["defines"]) {
+            profile.defines = defines;
+        }
+        if let Some(warnings) = get_strings(&definition["warnings"]) {
+            profile.warnings = warnings;
+        }
+
+        return Ok(profile);
+    }
+
+    /// The defines of the profile followed by the definitions the target is compiled with.
+    pub fn get_defines(&self, requirements: &UsageRequirements) -> Vec<String> {
+        let mut defines = self.defines.clone();
+        defines.extend(requirements.compile_definitions.iter().cloned());
+
+        return defines;
+    }
+
+    /// The flags of the profile followed by the options the target is compiled with, so targets
+    /// can override the profile.
+    pub fn get_compile_flags(&self, requirements: &UsageRequirements) -> Vec<String> {
+        let mut flags = vec![format!("-O{}", self.optimization)];
+        if self.debug_info {
+            flags.push("-g".to_owned());
+        }
+        flags.extend(self.warnings.iter().cloned());
+        flags.extend(requirements.compile_options.iter().cloned());
+
+        return flags;
+    }
+
+    /// Every profile gets its own build directory, so switching profiles does not rebuild everything.
+    pub fn get_build_dir_name(&self) -> String {
+        return format!("beaver_build_{}", self.name);
+    }
+}
+
+fn get_strings(value: &Value) -> Option<Vec<String>> {
+    let values = value.as_array()?;
+    return Some(values.iter().filter_map(|value| value.as_str()).map(|value| value.to_owned()).collect());
+}
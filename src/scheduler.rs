@@ -1,6 +1,14 @@
-use std::collections::{HashMap};
-use crate::graph_walker::{GraphVisitor};
-use crate::dependency_graph::{DependencyNode, DependencyGraph, Ref};
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::time::{Duration, Instant};
+use crate::build_observer::{BuildObserver, Job};
+use crate::compile_commands::{get_compile_instruction, write_compile_commands};
+use crate::dependency_graph::{DependencyNode, DependencyGraph, DependencyType, DependencyOptions, Ref, extend_unique_options};
+use crate::filesystem::{StatCache, join_path, read_depfile, read_dyndep};
+use crate::language::{Language, get_link_language};
+use crate::profile::{Profile};
+use crate::work_pool::{WorkPool, WorkEvent, WorkInstruction, WorkResult, get_depfile, get_object_file, hash_command_line};
 
 struct TargetStatus {
     // Target can only be built when all dependencies have been built.
@@ -8,49 +16,550 @@ struct TargetStatus {
     is_built: bool,
 }
 
+/// The build products of a target that its dependents need.
+#[derive(Default, Clone)]
 struct TargetData {
-    // TODO, define format, should be dynamic, based on target type
+    objects: Vec<String>,
+}
+
+/// Keeps track of the jobs of a target that is being built.
+struct TargetJobs {
+    number_of_unfinished_jobs: usize,
+    has_failed: bool,
+    // Set when one of the objects got rebuilt, the target then has to be linked again.
+    is_modified: bool,
+    // The link instruction can only be scheduled when all objects are compiled.
+    link_instruction: Option<WorkInstruction>,
 }
 
 /// Scheduler will walk the dependencygraph and whenever a node is free of dependencies it will
 /// mark the node unlocked.
 /// The scheduler use the workpool to process the unlocked nodes.
 /// Upon completion of the processing of an unlocked node its dependents will be updated.
-pub struct Scheduler {
+/// The status of the targets is kept between builds, so only targets that are invalidated will be
+/// processed again.
+pub struct Scheduler<'a> {
+    work_pool: &'a mut WorkPool,
+    build_dir: String,
+    profile: Profile,
+    stat_cache: StatCache,
+    // Hashes of the commands that produced the outputs, an output is rebuilt when its command changes.
+    command_hashes: HashMap<String, u64>,
     // TODO, replace hashmap by something smarter because we know how many nodes there are.
     target_status_map: HashMap<Ref<DependencyNode>, TargetStatus>,
     target_data_map: HashMap<Ref<DependencyNode>, TargetData>,
+    target_jobs_map: HashMap<Ref<DependencyNode>, TargetJobs>,
+    job_map: HashMap<usize, Job>,
+    // Files that custom commands reported in their dyndep files, and the command producing them.
+    dynamic_outputs: HashMap<String, Ref<DependencyNode>>,
+    // Targets that wait for a target because of an edge discovered during the build.
+    dynamic_dependents: HashMap<Ref<DependencyNode>, Vec<Ref<DependencyNode>>>,
+    // Jobs are only reported to the observer and pretend to succeed, nothing is written.
+    dry_run: bool,
+    dry_run_results: VecDeque<WorkResult>,
+    // Outputs of the jobs of a dry run, the files depending on them would be rebuilt too.
+    planned_outputs: HashSet<String>,
 }
 
-impl Scheduler {
-    pub fn new() -> Scheduler {
+impl Scheduler<'_> {
+    pub fn new(build_dir: String, profile: Profile, work_pool: &mut WorkPool) -> Scheduler<'_> {
+        let command_hashes = read_command_hashes(&build_dir);
         return Scheduler {
+            work_pool,
+            build_dir,
+            profile,
+            stat_cache: StatCache::new(),
+            command_hashes,
             target_status_map: HashMap::new(),
             target_data_map: HashMap::new(),
+            target_jobs_map: HashMap::new(),
+            job_map: HashMap::new(),
+            dynamic_outputs: HashMap::new(),
+            dynamic_dependents: HashMap::new(),
+            dry_run: false,
+            dry_run_results: VecDeque::new(),
+            planned_outputs: HashSet::new(),
         };
     }
 
-    pub fn build_all(&mut self, graph: &DependencyGraph) {
+    /// In a dry run the scheduler plans the jobs in the order they would be scheduled, without
+    /// running them.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Forget the status of all targets, needed when the graph is reconfigured.
+    pub fn reset(&mut self) {
+        self.target_status_map.clear();
+        self.target_data_map.clear();
+        self.stat_cache.clear();
+        self.dynamic_outputs.clear();
+    }
+
+    /// Marks the targets that contain one of the changed files, and everything that depends on
+    /// them, as unbuilt.
+    pub fn invalidate(&mut self, graph: &DependencyGraph, changed_files: &[String]) {
+        for changed_file in changed_files {
+            self.stat_cache.invalidate(changed_file);
+        }
+
+        let mut stack: Vec<_> = graph.get_nodes().into_iter().filter(|node| {
+            graph.get_files(*node).iter().any(|file| changed_files.contains(file))
+        }).collect();
+        while let Some(node) = stack.pop() {
+            if let Some(status) = self.target_status_map.get_mut(&node) {
+                if !status.is_built {
+                    continue;
+                }
+                status.is_built = false;
+            }
+            stack.extend(graph.get_dependents(node));
+        }
+    }
+
+    /// Builds the targets and their dependencies, returns whether the build succeeded.
+    pub fn build(&mut self, graph: &DependencyGraph, targets: &[Ref<DependencyNode>], observer: &mut dyn BuildObserver) -> bool {
+        observer.build_started(graph, targets);
+        // The graph or the flags may have changed since the last build.
+        if !self.dry_run {
+            if let Err(e) = write_compile_commands(graph, &self.profile, &self.build_dir) {
+                println!("ERROR: {}", e);
+            }
+        }
+
         // Step 1, create annotations for all nodes.
-        let roots = graph.get_roots();
-        for root in roots {
-            let name = graph.get_name(root);
-            println!("Scheduler starting from {}", name);
-            self.visit_node(graph, root);
-        }
-        
-        // This might not be optimal, but it is a strategy that is guaranteed to finish and build
-        // everything.
+        let mut nodes = vec![];
+        for target in targets {
+            self.visit_node(graph, *target, &mut nodes);
+        }
+
+        let mut unlocked_nodes = VecDeque::new();
+        for node in nodes.iter() {
+            let number_of_unbuilt_dependencies = graph.get_dependencies(*node).iter().filter(|dependency| {
+                return !self.target_status_map[dependency].is_built;
+            }).count();
+            let status = self.target_status_map.get_mut(node).unwrap();
+            status.number_of_unbuilt_dependencies = number_of_unbuilt_dependencies;
+            if !status.is_built {
+                observer.target_scheduled(graph, *node);
+            }
+            if !status.is_built && number_of_unbuilt_dependencies == 0 {
+                unlocked_nodes.push_back(*node);
+            }
+        }
+
         // Step 2, loop, while there are unbuilt nodes with no unbuilt dependencies.
-        //  Step 2a) Create work instructions for these nodes.
-        //  Step 2b) Schedule work instructions for these nodes.
-        //  Step 2c) Wait for work a complete target in the work results, store result in
-        //  target_data_map.
-        //  Step 2d) Decrease the number of unbuilt dependencies for dependents of the target. 
+        // Discovered edges are spliced in again when the custom commands finish.
+        self.dynamic_dependents.clear();
+        let nodes: HashSet<_> = nodes.into_iter().collect();
+        let mut build_succeeded = true;
+        loop {
+            //  Step 2a) Create and schedule work instructions for the unlocked nodes.
+            while let Some(node) = unlocked_nodes.pop_front() {
+                self.start_target(graph, node, observer);
+                self.try_finish_target(graph, node, &nodes, &mut unlocked_nodes, observer);
+            }
+
+            //  Step 2b) Wait for a job to complete and store the result.
+            let work_event = if self.dry_run {
+                self.dry_run_results.pop_front().map(WorkEvent::Finished)
+            } else {
+                let wait_start = Instant::now();
+                let work_event = self.work_pool.get_next_event_blocking();
+                observer.scheduler_idle(graph, wait_start.elapsed());
+                work_event
+            };
+            let work_result = match work_event {
+                Some(WorkEvent::Started { job_id, worker_id }) => {
+                    // Jobs of other builds, e.g. of a sequential build, are not ours to report.
+                    if let Some(job) = self.job_map.get(&job_id) {
+                        observer.job_started(graph, job, worker_id);
+                    }
+                    continue;
+                },
+                Some(WorkEvent::Finished(work_result)) => work_result,
+                None => break,
+            };
+            let job = self.job_map.remove(&work_result.job_id).unwrap();
+            self.stat_cache.invalidate(&job.output_file);
+            observer.job_finished(graph, &job, &work_result);
+            let target_jobs = self.target_jobs_map.get_mut(&job.target).unwrap();
+            target_jobs.number_of_unfinished_jobs -= 1;
+            if work_result.result.is_ok() {
+                self.command_hashes.insert(job.output_file.clone(), hash_command_line(&job.command_line));
+            } else {
+                target_jobs.has_failed = true;
+                build_succeeded = false;
+                self.command_hashes.remove(&job.output_file);
+            }
+
+            //  Step 2c) When a target is complete, update the dependents of the target.
+            self.try_finish_target(graph, job.target, &nodes, &mut unlocked_nodes, observer);
+        }
+
+        let number_of_unbuilt_targets = nodes.iter().filter(|node| !self.target_status_map[node].is_built).count();
+        build_succeeded = build_succeeded && number_of_unbuilt_targets == 0;
+        if !self.dry_run {
+            write_command_hashes(&self.build_dir, &self.command_hashes);
+        }
+        observer.build_finished(graph, build_succeeded, number_of_unbuilt_targets);
+
+        return build_succeeded;
     }
 
-    fn visit_node(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>) {
-        // TODO, insert to target status map and target data map.
-        // TODO, visit all children.
+    /// Adds annotations for the node and its dependencies, the nodes are added in post order.
+    fn visit_node(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>, nodes: &mut Vec<Ref<DependencyNode>>) {
+        if nodes.contains(&node) {
+            return;
+        }
+        for dependency in graph.get_dependencies(node) {
+            self.visit_node(graph, dependency, nodes);
+        }
+
+        self.target_status_map.entry(node).or_insert(TargetStatus {
+            number_of_unbuilt_dependencies: 0,
+            is_built: false,
+        });
+        nodes.push(node);
+    }
+
+    /// Collects the build products of the dependencies and schedules the compile jobs.
+    fn start_target(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>, observer: &mut dyn BuildObserver) {
+        observer.target_started(graph, node);
+        let mut target_data = TargetData::default();
+        for dependency in graph.get_dependencies(node) {
+            let dependency_data = &self.target_data_map[&dependency];
+            extend_unique(&mut target_data.objects, &dependency_data.objects);
+        }
+
+        let mut target_jobs = TargetJobs {
+            number_of_unfinished_jobs: 0,
+            has_failed: false,
+            is_modified: false,
+            link_instruction: None,
+        };
+
+        match graph.get_type(node) {
+            DependencyType::INTERFACE => {},
+            DependencyType::CUSTOM => {
+                if let Some(DependencyOptions::CustomCommandOptions { command, working_dir, outputs, .. }) = graph.get_options(node) {
+                    let instruction = WorkInstruction::Custom { command, working_dir, outputs: outputs.clone() };
+                    if let Some(reason) = self.get_custom_command_reason(&graph.get_files(node), &outputs, &instruction.get_command_line()) {
+                        self.schedule_job(graph, node, reason, instruction, observer);
+                        target_jobs.number_of_unfinished_jobs += 1;
+                        // Only the first output is invalidated when the job finishes, the other
+                        // outputs must not be stat before that either.
+                        for output in outputs.iter().skip(1) {
+                            self.stat_cache.invalidate(output);
+                        }
+                    }
+                }
+            },
+            DependencyType::IMPORTED => {
+                // Prebuilt artifacts are linked like objects, so a changed artifact causes a relink.
+                let mut objects = graph.get_files(node);
+                objects.extend(target_data.objects);
+                target_data.objects = objects;
+            },
+            DependencyType::LIBRARY | DependencyType::EXECUTABLE | DependencyType::TEST => {
+                let requirements = graph.get_usage_requirements(node);
+                let mut objects = vec![];
+                for source in graph.get_files(node) {
+                    let object_file = get_object_file(&self.build_dir, &source);
+                    objects.push(object_file.clone());
+
+                    let compile_instruction = get_compile_instruction(&self.profile, &self.build_dir, &requirements, &source);
+                    let Some(reason) = self.get_compile_reason(&source, &object_file, &compile_instruction.get_command_line()) else {
+                        continue;
+                    };
+                    self.schedule_job(graph, node, reason, compile_instruction, observer);
+                    target_jobs.number_of_unfinished_jobs += 1;
+                    target_jobs.is_modified = true;
+                }
+                // Our own objects come first, the linker resolves symbols from left to right.
+                objects.extend(target_data.objects);
+                target_data.objects = objects;
+
+                if graph.get_type(node) != DependencyType::LIBRARY {
+                    target_jobs.link_instruction = Some(self.get_link_instruction(graph, node, &target_data.objects));
+                }
+            },
+        }
+
+        self.target_data_map.insert(node, target_data);
+        self.target_jobs_map.insert(node, target_jobs);
+    }
+
+    /// Returns the instruction that links the executable after it has been built, e.g. to link it
+    /// again for installation.
+    pub fn get_built_link_instruction(&self, graph: &DependencyGraph, node: Ref<DependencyNode>) -> Option<WorkInstruction> {
+        if !self.target_status_map.get(&node)?.is_built || graph.get_type(node) == DependencyType::LIBRARY {
+            return None;
+        }
+
+        return Some(self.get_link_instruction(graph, node, &self.target_data_map.get(&node)?.objects));
+    }
+
+    fn get_link_instruction(&self, graph: &DependencyGraph, node: Ref<DependencyNode>, objects: &[String]) -> WorkInstruction {
+        let requirements = graph.get_usage_requirements(node);
+        // The options of the executable come first, then the requirements of its dependencies.
+        let (mut link_flags, mut link_libraries, linker_script) = match graph.get_options(node) {
+            Some(DependencyOptions::ExecutableOptions { link_flags, link_libraries, linker_script }) => (link_flags, link_libraries, linker_script),
+            _ => (vec![], vec![], None),
+        };
+        extend_unique_options(&mut link_flags, &requirements.link_options);
+        extend_unique(&mut link_libraries, &requirements.link_libraries);
+
+        return WorkInstruction::Link {
+            language: get_link_language(objects),
+            object_files: objects.to_vec(),
+            link_dirs: requirements.link_dirs,
+            link_flags,
+            linker_script,
+            link_libraries,
+            output_file: format!("{}/{}", self.build_dir, graph.get_name(node)),
+        };
+    }
+
+    /// Schedules the link job when the objects are complete, or marks the target built when all
+    /// its jobs are done.
+    fn try_finish_target(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>, nodes: &HashSet<Ref<DependencyNode>>, unlocked_nodes: &mut VecDeque<Ref<DependencyNode>>, observer: &mut dyn BuildObserver) {
+        let target_jobs = self.target_jobs_map.get_mut(&node).unwrap();
+        if target_jobs.number_of_unfinished_jobs > 0 {
+            return;
+        }
+        if target_jobs.has_failed {
+            observer.target_failed(graph, node);
+            self.target_jobs_map.remove(&node);
+            return;
+        }
+
+        if let Some(link_instruction) = target_jobs.link_instruction.take() {
+            let is_modified = target_jobs.is_modified;
+            if let WorkInstruction::Link { object_files, linker_script, output_file, .. } = &link_instruction {
+                let reason = if is_modified {
+                    Some("objects were rebuilt".to_owned())
+                } else {
+                    // The linker script is an input of the link as well.
+                    let inputs: Vec<_> = object_files.iter().chain(linker_script.iter()).cloned().collect();
+                    self.get_output_reason(&inputs, output_file, &link_instruction.get_command_line())
+                };
+                if let Some(reason) = reason {
+                    self.schedule_job(graph, node, reason, link_instruction, observer);
+                    self.target_jobs_map.get_mut(&node).unwrap().number_of_unfinished_jobs += 1;
+                    return;
+                }
+            }
+        }
+
+        if let Some(DependencyOptions::CustomCommandOptions { working_dir, dyndep: Some(dyndep), .. }) = graph.get_options(node) {
+            if !self.splice_dynamic_dependencies(graph, node, &dyndep, &working_dir, nodes) {
+                observer.target_failed(graph, node);
+                self.target_jobs_map.remove(&node);
+                return;
+            }
+        }
+
+        self.target_jobs_map.remove(&node);
+        self.target_status_map.get_mut(&node).unwrap().is_built = true;
+        let dynamic_dependents = self.dynamic_dependents.remove(&node).unwrap_or_default();
+        for dependent in graph.get_dependents(node).into_iter().chain(dynamic_dependents) {
+            if !nodes.contains(&dependent) {
+                continue;
+            }
+            let status = self.target_status_map.get_mut(&dependent).unwrap();
+            status.number_of_unbuilt_dependencies -= 1;
+            if !status.is_built && status.number_of_unbuilt_dependencies == 0 {
+                unlocked_nodes.push_back(dependent);
+            }
+        }
+    }
+
+    /// Reads the dyndep file of a finished custom command. Targets that need a file produced by a
+    /// target that is not built yet wait for it, like they wait for their dependencies. Only
+    /// targets that are still waiting can get extra dependencies, so the targets reading the
+    /// dyndep file should require the custom command. Returns false when the file can not be read.
+    fn splice_dynamic_dependencies(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>, dyndep_file: &str, working_dir: &str, nodes: &HashSet<Ref<DependencyNode>>) -> bool {
+        let Some(dyndep) = read_dyndep(dyndep_file, working_dir) else {
+            // A dry run only knows the edges of a previous build.
+            return self.dry_run;
+        };
+
+        for (file, extra_outputs) in dyndep.outputs {
+            let producer = find_producer(graph, &self.dynamic_outputs, &file).unwrap_or(node);
+            for extra_output in extra_outputs {
+                self.stat_cache.invalidate(&extra_output);
+                self.dynamic_outputs.insert(extra_output, producer);
+            }
+        }
+
+        for (file, extra_inputs) in dyndep.inputs {
+            let Some(consumer) = graph.get_nodes().into_iter().find(|node| graph.get_files(*node).contains(&file)) else {
+                continue;
+            };
+            for extra_input in extra_inputs {
+                let Some(producer) = find_producer(graph, &self.dynamic_outputs, &extra_input) else {
+                    continue;
+                };
+                // Producers outside of this build will not be built, there is nothing to wait for.
+                let producer_is_built = !nodes.contains(&producer) || self.target_status_map[&producer].is_built;
+                let dependents = self.dynamic_dependents.entry(producer).or_default();
+                if producer == consumer || producer_is_built || dependents.contains(&consumer) || graph.get_dependencies(consumer).contains(&producer) {
+                    continue;
+                }
+                let Some(status) = self.target_status_map.get_mut(&consumer) else {
+                    continue;
+                };
+                if status.is_built || status.number_of_unbuilt_dependencies == 0 {
+                    continue; // Already released, too late to wait.
+                }
+                status.number_of_unbuilt_dependencies += 1;
+                dependents.push(consumer);
+            }
+        }
+
+        return true;
+    }
+
+    fn schedule_job(&mut self, graph: &DependencyGraph, node: Ref<DependencyNode>, reason: String, instruction: WorkInstruction, observer: &mut dyn BuildObserver) {
+        let description = instruction.get_description();
+        let command_line = instruction.get_command_line();
+        let output_file = instruction.get_output_file().to_owned();
+        let source_file = instruction.get_source_file().map(|source_file| source_file.to_owned());
+        let job_id = if self.dry_run {
+            let outputs = match &instruction {
+                WorkInstruction::Custom { outputs, .. } => outputs.clone(),
+                _ => vec![output_file.clone()],
+            };
+            // Depfiles name the same file differently, e.g. "src/../include/a.h".
+            self.planned_outputs.extend(outputs.iter().map(|output| join_path(".", output)));
+            let job_id = self.job_map.keys().max().map_or(0, |job_id| job_id + 1);
+            self.dry_run_results.push_back(WorkResult { job_id, result: Ok(String::new()), start: Instant::now(), duration: Duration::ZERO, timed_out: false, process_status: None });
+            job_id
+        } else {
+            self.work_pool.schedule_work(instruction)
+        };
+
+        let job = Job {
+            id: job_id,
+            target: node,
+            description,
+            reason,
+            command_line,
+            source_file,
+            output_file,
+        };
+        observer.job_scheduled(graph, &job);
+        self.job_map.insert(job_id, job);
+    }
+
+    /// Returns why the source has to be compiled, or None when the object is up to date.
+    fn get_compile_reason(&mut self, source_file: &str, object_file: &str, command_line: &[String]) -> Option<String> {
+        let Some(object_modified) = self.stat_cache.get_modified(object_file) else {
+            return Some("object is missing".to_owned());
+        };
+        if self.command_hashes.get(object_file) != Some(&hash_command_line(command_line)) {
+            return Some("command changed".to_owned());
+        }
+        // Sources that are not preprocessed do not include anything.
+        let prerequisites = if Language::from_source(source_file).unwrap().uses_preprocessor() {
+            let depfile = get_depfile(object_file);
+            let Some(prerequisites) = read_depfile(&depfile) else {
+                return Some("dependency file is missing".to_owned());
+            };
+            prerequisites
+        } else {
+            vec![]
+        };
+
+        for prerequisite in [source_file.to_owned()].iter().chain(prerequisites.iter()) {
+            if self.planned_outputs.contains(&join_path(".", prerequisite)) {
+                return Some(format!("{} will be rebuilt", prerequisite));
+            }
+            match self.stat_cache.get_modified(prerequisite) {
+                Some(modified) if modified <= object_modified => {},
+                Some(_) => {
+                    return Some(format!("{} changed", prerequisite));
+                },
+                None => {
+                    return Some(format!("{} is missing", prerequisite));
+                },
+            }
+        }
+
+        return None;
+    }
+
+    /// Returns why the command has to run, or None when all outputs are up to date.
+    fn get_custom_command_reason(&mut self, input_files: &[String], outputs: &[String], command_line: &[String]) -> Option<String> {
+        for output in outputs.iter().skip(1) {
+            if self.stat_cache.get_modified(output).is_none() {
+                return Some(format!("{} is missing", output));
+            }
+        }
+
+        return self.get_output_reason(input_files, &outputs[0], command_line);
+    }
+
+    /// Returns why the output has to be linked or generated again, or None when the output is up to date.
+    fn get_output_reason(&mut self, input_files: &[String], output_file: &str, command_line: &[String]) -> Option<String> {
+        let Some(output_modified) = self.stat_cache.get_modified(output_file) else {
+            return Some("output is missing".to_owned());
+        };
+        if self.command_hashes.get(output_file) != Some(&hash_command_line(command_line)) {
+            return Some("command changed".to_owned());
+        }
+        for input_file in input_files {
+            if self.planned_outputs.contains(&join_path(".", input_file)) {
+                return Some(format!("{} will be rebuilt", input_file));
+            }
+            match self.stat_cache.get_modified(input_file) {
+                Some(modified) if modified <= output_modified => {},
+                _ => {
+                    return Some(format!("{} changed", input_file));
+                },
+            }
+        }
+
+        return None;
+    }
+}
+
+/// Returns the custom command that produces the file, declared or discovered.
+fn find_producer(graph: &DependencyGraph, dynamic_outputs: &HashMap<String, Ref<DependencyNode>>, file: &str) -> Option<Ref<DependencyNode>> {
+    if let Some(producer) = dynamic_outputs.get(file) {
+        return Some(*producer);
+    }
+
+    return graph.get_nodes().into_iter().find(|node| {
+        return matches!(graph.get_options(*node), Some(DependencyOptions::CustomCommandOptions { outputs, .. }) if outputs.iter().any(|output| output == file));
+    });
+}
+
+fn get_command_hashes_file(build_dir: &str) -> String {
+    return format!("{}/.beaver_commands", build_dir);
+}
+
+/// Reads the command hashes of a previous build, every line is of the form "<hash> <output>".
+fn read_command_hashes(build_dir: &str) -> HashMap<String, u64> {
+    let content = fs::read_to_string(get_command_hashes_file(build_dir)).unwrap_or_default();
+    return content.lines().filter_map(|line| {
+        let (hash, output_file) = line.split_once(' ')?;
+        return Some((output_file.to_owned(), u64::from_str_radix(hash, 16).ok()?));
+    }).collect();
+}
+
+fn write_command_hashes(build_dir: &str, command_hashes: &HashMap<String, u64>) {
+    let content: String = command_hashes.iter().map(|(output_file, hash)| format!("{:016x} {}\n", hash, output_file)).collect();
+    if let Err(e) = fs::write(get_command_hashes_file(build_dir), content) {
+        println!("Could not store command hashes: {}", e);
+    }
+}
+
+fn extend_unique(items: &mut Vec<String>, new_items: &[String]) {
+    for item in new_items {
+        if !items.contains(item) {
+            items.push(item.clone());
+        }
     }
 }
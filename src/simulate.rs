@@ -0,0 +1,286 @@
+// Replays the scheduling of a full build offline, with the job durations of the build log, to
+// predict the wall time for different numbers of workers. Like the scheduler, a target is started
+// when its dependencies are built, and it is linked when its sources are compiled. Like the work
+// pool, a link takes several slots of the workers.
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyOptions, DependencyType, Ref};
+use crate::throttle::{LINK_WEIGHT, get_slots};
+use crate::work_pool::{get_object_file};
+
+/// How the next job is picked from the jobs that are ready to run.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SchedulingPolicy {
+    // In the order the jobs became ready, like the scheduler does.
+    Fifo,
+    // The job with the longest chain of work after it first.
+    CriticalPath,
+}
+
+struct SimulatedJob {
+    target: Ref<DependencyNode>,
+    duration: f64,
+    // Link jobs wait for the compile jobs of their target.
+    is_compile: bool,
+    // The slots of the workers the job takes, see WorkInstruction::get_weight.
+    weight: usize,
+}
+
+pub struct SimulationResult {
+    pub wall_time: f64,
+    // The fraction of the time the slots of the workers were taken, a link takes several.
+    pub utilization: f64,
+}
+
+pub struct Simulation {
+    targets: Vec<Ref<DependencyNode>>,
+    jobs: Vec<SimulatedJob>,
+    // The longest chain of work that starts with the job.
+    priorities: Vec<f64>,
+    pub number_of_estimated_jobs: usize,
+}
+
+impl Simulation {
+    /// Creates the jobs a full build of the targets would run. Jobs missing from the log are
+    /// assumed to take the average time of the jobs in the log.
+    pub fn new(graph: &DependencyGraph, targets: &[Ref<DependencyNode>], build_dir: &str, durations: &HashMap<String, f64>) -> Simulation {
+        let mut nodes = targets.to_vec();
+        for target in targets {
+            for dependency in graph.get_all_dependencies(*target) {
+                if !nodes.contains(&dependency) {
+                    nodes.push(dependency);
+                }
+            }
+        }
+
+        let average_duration = durations.values().sum::<f64>() / durations.len().max(1) as f64;
+        let mut number_of_estimated_jobs = 0;
+        let mut get_duration = |output_file: &str| {
+            return durations.get(output_file).copied().unwrap_or_else(|| {
+                number_of_estimated_jobs += 1;
+                average_duration
+            });
+        };
+
+        let mut jobs = vec![];
+        for node in nodes.iter() {
+            match graph.get_type(*node) {
+                DependencyType::CUSTOM => {
+                    if let Some(DependencyOptions::CustomCommandOptions { outputs, .. }) = graph.get_options(*node) {
+                        jobs.push(SimulatedJob { target: *node, duration: get_duration(&outputs[0]), is_compile: false, weight: 1 });
+                    }
+                },
+                DependencyType::LIBRARY | DependencyType::EXECUTABLE | DependencyType::TEST => {
+                    for source in graph.get_files(*node) {
+                        jobs.push(SimulatedJob { target: *node, duration: get_duration(&get_object_file(build_dir, &source)), is_compile: true, weight: 1 });
+                    }
+                    if graph.get_type(*node) != DependencyType::LIBRARY {
+                        let output_file = format!("{}/{}", build_dir, graph.get_name(*node));
+                        jobs.push(SimulatedJob { target: *node, duration: get_duration(&output_file), is_compile: false, weight: LINK_WEIGHT });
+                    }
+                },
+                DependencyType::INTERFACE | DependencyType::IMPORTED => {},
+            }
+        }
+
+        let mut simulation = Simulation {
+            targets: nodes,
+            jobs,
+            priorities: vec![],
+            number_of_estimated_jobs,
+        };
+        simulation.priorities = simulation.get_priorities(graph);
+        return simulation;
+    }
+
+    pub fn get_number_of_jobs(&self) -> usize {
+        return self.jobs.len();
+    }
+
+    fn get_priorities(&self, graph: &DependencyGraph) -> Vec<f64> {
+        let mut tails = HashMap::new();
+        return self.jobs.iter().map(|job| {
+            let link_duration = if job.is_compile { self.get_link_duration(job.target) } else { 0.0 };
+            return job.duration + link_duration + self.get_tail(graph, job.target, &mut tails);
+        }).collect();
+    }
+
+    fn get_link_duration(&self, target: Ref<DependencyNode>) -> f64 {
+        return self.jobs.iter().filter(|job| job.target == target && !job.is_compile).map(|job| job.duration).sum();
+    }
+
+    /// The longest chain of work of the dependents of the target, after the target is built.
+    fn get_tail(&self, graph: &DependencyGraph, target: Ref<DependencyNode>, tails: &mut HashMap<Ref<DependencyNode>, f64>) -> f64 {
+        if let Some(tail) = tails.get(&target) {
+            return *tail;
+        }
+        let mut tail: f64 = 0.0;
+        for dependent in graph.get_dependents(target) {
+            if !self.targets.contains(&dependent) {
+                continue;
+            }
+            let longest_compile = self.jobs.iter().filter(|job| job.target == dependent && job.is_compile).map(|job| job.duration).fold(0.0, f64::max);
+            let dependent_time = longest_compile + self.get_link_duration(dependent);
+            tail = tail.max(dependent_time + self.get_tail(graph, dependent, tails));
+        }
+        tails.insert(target, tail);
+
+        return tail;
+    }
+
+    /// Simulates the build with the number of workers.
+    pub fn run(&self, graph: &DependencyGraph, number_of_workers: usize, policy: SchedulingPolicy) -> SimulationResult {
+        let targets: HashSet<_> = self.targets.iter().copied().collect();
+        let mut number_of_unbuilt_dependencies: HashMap<_, _> = self.targets.iter().map(|target| {
+            return (*target, graph.get_dependencies(*target).iter().filter(|dependency| targets.contains(dependency)).count());
+        }).collect();
+        let mut number_of_unfinished_jobs: HashMap<Ref<DependencyNode>, usize> = HashMap::new();
+
+        let mut time = 0.0;
+        let mut ready_jobs: Vec<usize> = vec![];
+        // The jobs on the workers, with the time they finish.
+        let mut running_jobs: Vec<(f64, usize)> = vec![];
+        let mut used_slots = 0;
+        let mut unlocked_targets: VecDeque<_> = self.targets.iter().copied().filter(|target| number_of_unbuilt_dependencies[target] == 0).collect();
+        loop {
+            // Start the unlocked targets in the order they were unlocked, targets without jobs are
+            // built right away.
+            while let Some(target) = unlocked_targets.pop_front() {
+                let compile_jobs: Vec<_> = (0..self.jobs.len()).filter(|i| self.jobs[*i].target == target && self.jobs[*i].is_compile).collect();
+                let other_jobs: Vec<_> = (0..self.jobs.len()).filter(|i| self.jobs[*i].target == target && !self.jobs[*i].is_compile).collect();
+                let jobs = if compile_jobs.is_empty() { other_jobs } else { compile_jobs };
+                if jobs.is_empty() {
+                    self.finish_target(graph, target, &targets, &mut number_of_unbuilt_dependencies, &mut unlocked_targets);
+                    continue;
+                }
+                number_of_unfinished_jobs.insert(target, jobs.len());
+                ready_jobs.extend(jobs);
+            }
+
+            while running_jobs.len() < number_of_workers && !ready_jobs.is_empty() {
+                let next = match policy {
+                    SchedulingPolicy::Fifo => 0,
+                    SchedulingPolicy::CriticalPath => (0..ready_jobs.len()).max_by(|a, b| self.priorities[ready_jobs[*a]].total_cmp(&self.priorities[ready_jobs[*b]])).unwrap(),
+                };
+                // Like the throttle, the next job is not passed by jobs that fit.
+                let slots = get_slots(self.jobs[ready_jobs[next]].weight, number_of_workers);
+                if used_slots + slots > number_of_workers {
+                    break;
+                }
+                let job = ready_jobs.remove(next);
+                used_slots += slots;
+                running_jobs.push((time + self.jobs[job].duration, job));
+            }
+
+            let Some(first) = (0..running_jobs.len()).min_by(|a, b| running_jobs[*a].0.total_cmp(&running_jobs[*b].0)) else {
+                break;
+            };
+            let (end, job) = running_jobs.remove(first);
+            time = end;
+            used_slots -= get_slots(self.jobs[job].weight, number_of_workers);
+            let target = self.jobs[job].target;
+            let unfinished_jobs = number_of_unfinished_jobs.get_mut(&target).unwrap();
+            *unfinished_jobs -= 1;
+            if *unfinished_jobs > 0 {
+                continue;
+            }
+            // The compiles are done, now the target can be linked.
+            let link_jobs: Vec<_> = (0..self.jobs.len()).filter(|i| self.jobs[*i].target == target && !self.jobs[*i].is_compile).collect();
+            if self.jobs[job].is_compile && !link_jobs.is_empty() {
+                number_of_unfinished_jobs.insert(target, link_jobs.len());
+                ready_jobs.extend(link_jobs);
+                continue;
+            }
+            self.finish_target(graph, target, &targets, &mut number_of_unbuilt_dependencies, &mut unlocked_targets);
+        }
+
+        let job_time: f64 = self.jobs.iter().map(|job| job.duration * get_slots(job.weight, number_of_workers) as f64).sum();
+        return SimulationResult {
+            wall_time: time,
+            utilization: if time > 0.0 { job_time / (time * number_of_workers as f64) } else { 0.0 },
+        };
+    }
+
+    fn finish_target(&self, graph: &DependencyGraph, target: Ref<DependencyNode>, targets: &HashSet<Ref<DependencyNode>>, number_of_unbuilt_dependencies: &mut HashMap<Ref<DependencyNode>, usize>, unlocked_targets: &mut VecDeque<Ref<DependencyNode>>) {
+        for dependent in graph.get_dependents(target) {
+            if !targets.contains(&dependent) {
+                continue;
+            }
+            let count = number_of_unbuilt_dependencies.get_mut(&dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                unlocked_targets.push_back(dependent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependency_graph::{Visibility};
+
+    fn add_generator(graph: &mut DependencyGraph, name: &str) -> Ref<DependencyNode> {
+        let options = DependencyOptions::CustomCommandOptions {
+            command: vec!["true".to_owned()],
+            working_dir: ".".to_owned(),
+            outputs: vec![format!("{}.out", name)],
+            dyndep: None,
+        };
+        return graph.add_custom_command(name, vec![], options);
+    }
+
+    #[test]
+    fn run_runs_independent_jobs_in_parallel() {
+        let mut graph = DependencyGraph::new();
+        let first = add_generator(&mut graph, "first");
+        let second = add_generator(&mut graph, "second");
+        let durations = HashMap::from([("first.out".to_owned(), 1.0), ("second.out".to_owned(), 1.0)]);
+        let simulation = Simulation::new(&graph, &[first, second], "build", &durations);
+
+        let result = simulation.run(&graph, 2, SchedulingPolicy::Fifo);
+        assert_eq!(result.wall_time, 1.0);
+        assert_eq!(result.utilization, 1.0);
+        assert_eq!(simulation.run(&graph, 1, SchedulingPolicy::Fifo).wall_time, 2.0);
+    }
+
+    #[test]
+    fn run_links_after_the_compiles_and_dependencies() {
+        let mut graph = DependencyGraph::new();
+        let generator = add_generator(&mut graph, "generator");
+        let executable = graph.add_executable("app", vec!["a.c".to_owned(), "b.c".to_owned()]);
+        graph.add_requirement(executable, generator, Visibility::PRIVATE);
+        let durations = HashMap::from([
+            ("generator.out".to_owned(), 1.0),
+            (get_object_file("build", "a.c"), 1.0),
+            (get_object_file("build", "b.c"), 2.0),
+            ("build/app".to_owned(), 1.0),
+        ]);
+        let simulation = Simulation::new(&graph, &[executable], "build", &durations);
+        assert_eq!(simulation.number_of_estimated_jobs, 0);
+
+        assert_eq!(simulation.run(&graph, 2, SchedulingPolicy::Fifo).wall_time, 4.0);
+        assert_eq!(simulation.run(&graph, 1, SchedulingPolicy::CriticalPath).wall_time, 5.0);
+    }
+
+    #[test]
+    fn run_gives_links_the_weight_of_the_throttle() {
+        let mut graph = DependencyGraph::new();
+        let first = graph.add_executable("first", vec!["first.c".to_owned()]);
+        let second = graph.add_executable("second", vec!["second.c".to_owned()]);
+        let durations = HashMap::from([
+            (get_object_file("build", "first.c"), 1.0),
+            (get_object_file("build", "second.c"), 1.0),
+            ("build/first".to_owned(), 1.0),
+            ("build/second".to_owned(), 1.0),
+        ]);
+        let simulation = Simulation::new(&graph, &[first, second], "build", &durations);
+
+        // Each link takes 4 slots, so they only run side by side with 8 workers.
+        assert_eq!(simulation.run(&graph, 4, SchedulingPolicy::Fifo).wall_time, 3.0);
+        let result = simulation.run(&graph, 8, SchedulingPolicy::Fifo);
+        assert_eq!(result.wall_time, 2.0);
+        // The compiles take 2 of 8 slots for a second, the links all 8 for a second.
+        assert_eq!(result.utilization, 0.625);
+    }
+}
@@ -0,0 +1,160 @@
+// Runs the tests of the project on the work pool and reports their results.
+#![allow(clippy::needless_return)]
+use std::fs;
+use std::time::{Duration};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, DependencyOptions, Ref};
+use crate::work_pool::{WorkPool, WorkInstruction};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+pub struct TestResult {
+    pub name: String,
+    pub status: TestStatus,
+    pub duration: Duration,
+    // Everything the test wrote to stdout and stderr.
+    pub output: String,
+}
+
+/// Returns whether the name matches the filter, a comma separated list of patterns in which `*`
+/// matches anything. Patterns starting with `-` exclude names, e.g. "list_*,-list_slow_test".
+pub fn matches_filter(name: &str, filter: &str) -> bool {
+    let mut is_included = false;
+    let mut has_include_patterns = false;
+    for pattern in filter.split(',').filter(|pattern| !pattern.is_empty()) {
+        match pattern.strip_prefix('-') {
+            Some(pattern) => {
+                if matches_pattern(name, pattern) {
+                    return false;
+                }
+            },
+            None => {
+                has_include_patterns = true;
+                is_included = is_included || matches_pattern(name, pattern);
+            },
+        }
+    }
+
+    return is_included || !has_include_patterns;
+}
+
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    return match pattern.split_once('*') {
+        None => name == pattern,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=name.len()).filter(|index| name.is_char_boundary(*index)).any(|index| matches_pattern(&name[index..], rest))
+        },
+    };
+}
+
+// Tests that specify no timeout of their own are killed after a minute.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs the built tests in parallel. The timeout given on the command line wins over the timeout
+/// the test specifies, which wins over the default.
+pub fn run_tests(graph: &DependencyGraph, tests: &[Ref<DependencyNode>], build_dir: &str, timeout: Option<Duration>, work_pool: &mut WorkPool) -> Vec<TestResult> {
+    let mut job_tests = vec![];
+    for test in tests {
+        let (working_dir, test_timeout) = match graph.get_options(*test) {
+            Some(DependencyOptions::TestOptions { working_dir, timeout_seconds }) => (working_dir, timeout.or(timeout_seconds.map(Duration::from_secs))),
+            _ => (".".to_owned(), timeout),
+        };
+        let instruction = WorkInstruction::RunTest {
+            executable: format!("{}/{}", build_dir, graph.get_name(*test)),
+            working_dir,
+            timeout: test_timeout.unwrap_or(DEFAULT_TIMEOUT),
+        };
+        let job_id = work_pool.schedule_work(instruction);
+        job_tests.push((job_id, graph.get_name(*test)));
+    }
+
+    let mut results = vec![];
+    while let Some(work_result) = work_pool.get_next_result_blocking() {
+        let Some((_, name)) = job_tests.iter().find(|(job_id, _)| *job_id == work_result.job_id) else {
+            continue;
+        };
+        let (status, output) = match work_result.result {
+            Ok(output) => (TestStatus::Passed, output),
+            Err(output) if work_result.timed_out => (TestStatus::TimedOut, output),
+            Err(output) => (TestStatus::Failed, output),
+        };
+        results.push(TestResult {
+            name: name.clone(),
+            status,
+            duration: work_result.duration,
+            output,
+        });
+    }
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+
+    return results;
+}
+
+/// Writes the results in the JUnit XML format, as read by most CI systems.
+pub fn write_junit_xml(file: &str, results: &[TestResult]) -> Result<(), String> {
+    let number_of_failures = results.iter().filter(|result| result.status != TestStatus::Passed).count();
+    let total_duration: Duration = results.iter().map(|result| result.duration).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n", results.len(), number_of_failures, total_duration.as_secs_f64()));
+    xml.push_str(&format!("  <testsuite name=\"beaver\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n", results.len(), number_of_failures, total_duration.as_secs_f64()));
+    for result in results {
+        xml.push_str(&format!("    <testcase name=\"{}\" classname=\"beaver\" time=\"{:.3}\">\n", escape_xml(&result.name), result.duration.as_secs_f64()));
+        match result.status {
+            TestStatus::Passed => {},
+            TestStatus::Failed => xml.push_str("      <failure message=\"failed\"/>\n"),
+            TestStatus::TimedOut => xml.push_str("      <failure message=\"timed out\"/>\n"),
+        }
+        xml.push_str(&format!("      <system-out>{}</system-out>\n", escape_xml(&result.output)));
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+
+    return fs::write(file, xml).map_err(|e| format!("Could not write {}: {}", file, e));
+}
+
+/// Escapes the text for XML. Control characters are not allowed in XML 1.0 even when escaped, e.g.
+/// the escape sequences of colored output, so they are replaced with U+FFFD.
+fn escape_xml(text: &str) -> String {
+    let text: String = text.chars().map(|c| match c {
+        '\t' | '\n' | '\r' => c,
+        c if c < ' ' || c == '\u{FFFE}' || c == '\u{FFFF}' => char::REPLACEMENT_CHARACTER,
+        c => c,
+    }).collect();
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_filter_includes_patterns() {
+        assert!(matches_filter("list_test", "list_test"));
+        assert!(matches_filter("list_test", "vec_test,list_*"));
+        assert!(matches_filter("list_test", "*_test"));
+        assert!(matches_filter("list_test", "l*t_*t"));
+        assert!(!matches_filter("list_test", "vec_*"));
+        assert!(!matches_filter("list_test", "list"));
+    }
+
+    #[test]
+    fn matches_filter_excludes_patterns() {
+        assert!(matches_filter("list_test", "-vec_*"));
+        assert!(!matches_filter("list_slow_test", "list_*,-*_slow_*"));
+        assert!(!matches_filter("list_test", "-list_test,list_*"));
+        assert!(matches_filter("list_test", ""));
+    }
+
+    #[test]
+    fn escape_xml_replaces_control_characters() {
+        assert_eq!(escape_xml("\u{1b}[31mred\u{1b}[0m <a & \"b\">\ttab\n"), "\u{FFFD}[31mred\u{FFFD}[0m &lt;a &amp; &quot;b&quot;&gt;\ttab\n");
+    }
+}
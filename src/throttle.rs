@@ -0,0 +1,214 @@
+// Decides when the jobs of the work pool may start. Every job takes slots of the pool, heavy jobs
+// like links take more than one, and the memory it used in its last build. Jobs are admitted in
+// the order they were scheduled, so a heavy job is not passed forever by light ones.
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration};
+
+/// The slots a link takes, links need far more memory than compiles.
+pub const LINK_WEIGHT: usize = 4;
+
+/// The slots a job of the weight takes. A job never takes more slots than there are, or it could
+/// never start.
+pub fn get_slots(weight: usize, number_of_slots: usize) -> usize {
+    return weight.min(number_of_slots);
+}
+
+/// Limits besides the number of slots, set with -l and --memory-budget.
+#[derive(Default)]
+pub struct ResourceLimits {
+    // No job is started while the load average is this high, unless none is running.
+    pub max_load: Option<f64>,
+    // In kilobytes, the memory the running jobs may use together.
+    pub memory_budget: Option<u64>,
+    // The peak memory use of the jobs in the build log by their output file, in kilobytes.
+    pub peak_rss: HashMap<String, u64>,
+}
+
+/// What an admitted job holds until it is done.
+pub struct Admission {
+    slots: usize,
+    memory: u64,
+}
+
+struct ThrottleState {
+    used_slots: usize,
+    used_memory: u64,
+    number_of_running_jobs: usize,
+    next_ticket: usize,
+    // The ticket of the job to admit next.
+    serving: usize,
+}
+
+pub struct Throttle {
+    slots: usize,
+    limits: ResourceLimits,
+    // Jobs missing from the build log are assumed to use as much memory as the average job.
+    average_peak_rss: u64,
+    state: Mutex<ThrottleState>,
+    condvar: Condvar,
+}
+
+impl Throttle {
+    pub fn new(slots: usize, limits: ResourceLimits) -> Throttle {
+        let average_peak_rss = limits.peak_rss.values().sum::<u64>() / limits.peak_rss.len().max(1) as u64;
+        return Throttle {
+            slots,
+            limits,
+            average_peak_rss,
+            state: Mutex::new(ThrottleState {
+                used_slots: 0,
+                used_memory: 0,
+                number_of_running_jobs: 0,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            condvar: Condvar::new(),
+        };
+    }
+
+    /// Blocks until the job fits, a job that fits nowhere is admitted when nothing else runs.
+    pub fn admit(&self, weight: usize, output_file: &str) -> Admission {
+        let admission = Admission {
+            slots: get_slots(weight, self.slots),
+            memory: self.limits.peak_rss.get(output_file).copied().unwrap_or(self.average_peak_rss),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || !self.fits(&state, &admission) {
+            state = match self.limits.max_load {
+                // Nothing is notified when the load drops.
+                Some(_) => self.condvar.wait_timeout(state, Duration::from_millis(500)).unwrap().0,
+                None => self.condvar.wait(state).unwrap(),
+            };
+        }
+        state.serving += 1;
+        state.used_slots += admission.slots;
+        state.used_memory += admission.memory;
+        state.number_of_running_jobs += 1;
+        // The next job may fit as well.
+        self.condvar.notify_all();
+
+        return admission;
+    }
+
+    pub fn release(&self, admission: Admission) {
+        let mut state = self.state.lock().unwrap();
+        state.used_slots -= admission.slots;
+        state.used_memory -= admission.memory;
+        state.number_of_running_jobs -= 1;
+        self.condvar.notify_all();
+    }
+
+    fn fits(&self, state: &ThrottleState, admission: &Admission) -> bool {
+        if state.number_of_running_jobs == 0 {
+            return true;
+        }
+        if state.used_slots + admission.slots > self.slots {
+            return false;
+        }
+        if self.limits.memory_budget.is_some_and(|memory_budget| state.used_memory + admission.memory > memory_budget) {
+            return false;
+        }
+        if self.limits.max_load.is_some_and(|max_load| get_load_average() >= max_load) {
+            return false;
+        }
+
+        return true;
+    }
+}
+
+/// The load average of the last minute.
+fn get_load_average() -> f64 {
+    let mut load_average = [0.0; 1];
+    if unsafe { libc::getloadavg(load_average.as_mut_ptr(), 1) } != 1 {
+        return 0.0;
+    }
+
+    return load_average[0];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Admits the job on another thread, the receiver gets the admission once it is admitted.
+    fn admit_in_background(throttle: &Arc<Throttle>, weight: usize, output_file: &str) -> Receiver<Admission> {
+        let (sender, receiver) = channel();
+        let throttle = throttle.clone();
+        let output_file = output_file.to_owned();
+        thread::spawn(move || sender.send(throttle.admit(weight, &output_file)).unwrap());
+        return receiver;
+    }
+
+    fn wait_for_tickets(throttle: &Throttle, number_of_tickets: usize) {
+        while throttle.state.lock().unwrap().next_ticket < number_of_tickets {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    const BLOCKED: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn admit_clamps_heavy_jobs_to_the_slots() {
+        let throttle = Throttle::new(2, ResourceLimits::default());
+        let admission = throttle.admit(LINK_WEIGHT, "app");
+        assert_eq!(admission.slots, 2);
+        throttle.release(admission);
+    }
+
+    #[test]
+    fn admit_waits_for_free_slots() {
+        let throttle = Arc::new(Throttle::new(4, ResourceLimits::default()));
+        let link = throttle.admit(LINK_WEIGHT, "app");
+        let compile = admit_in_background(&throttle, 1, "main.c.o");
+        assert!(compile.recv_timeout(BLOCKED).is_err());
+
+        throttle.release(link);
+        throttle.release(compile.recv().unwrap());
+    }
+
+    #[test]
+    fn admit_keeps_the_order_of_the_jobs() {
+        let throttle = Arc::new(Throttle::new(4, ResourceLimits::default()));
+        let compile = throttle.admit(1, "a.c.o");
+        let link = admit_in_background(&throttle, LINK_WEIGHT, "app");
+        wait_for_tickets(&throttle, 2);
+        // Fits, but the link came first.
+        let next_compile = admit_in_background(&throttle, 1, "b.c.o");
+        assert!(next_compile.recv_timeout(BLOCKED).is_err());
+
+        throttle.release(compile);
+        throttle.release(link.recv().unwrap());
+        throttle.release(next_compile.recv().unwrap());
+    }
+
+    #[test]
+    fn admit_stays_within_the_memory_budget() {
+        let limits = ResourceLimits {
+            max_load: None,
+            memory_budget: Some(100),
+            peak_rss: HashMap::from([("a.c.o".to_owned(), 60), ("b.c.o".to_owned(), 60), ("c.c.o".to_owned(), 30)]),
+        };
+        let throttle = Arc::new(Throttle::new(4, limits));
+        let first = throttle.admit(1, "a.c.o");
+        let second = admit_in_background(&throttle, 1, "b.c.o");
+        assert!(second.recv_timeout(BLOCKED).is_err());
+
+        throttle.release(first);
+        let second = second.recv().unwrap();
+        let third = throttle.admit(1, "c.c.o");
+        throttle.release(second);
+        throttle.release(third);
+        // Jobs missing from the log use the average memory.
+        let unknown = throttle.admit(1, "d.c.o");
+        assert_eq!(unknown.memory, 50);
+        throttle.release(unknown);
+    }
+}
@@ -0,0 +1,122 @@
+// Records the timeline of a build in the Chrome trace event format, which chrome://tracing and
+// Perfetto can show. Every worker of the work pool gets its own track with the jobs it ran, the
+// scheduler track shows configuring, the builds and when the scheduler waited for the workers.
+// https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::build_observer::{BuildObserver, Job};
+use crate::dependency_graph::{DependencyGraph, DependencyNode, Ref};
+use crate::work_pool::{WorkResult};
+
+// The scheduler runs on the main thread, workers get the tracks after it.
+const SCHEDULER_TRACK: usize = 0;
+
+pub struct TraceObserver {
+    file: String,
+    start: Instant,
+    events: Vec<Value>,
+    // Tracks that have been named already.
+    tracks: HashSet<usize>,
+    configure_start: Option<Instant>,
+    build_start: Option<Instant>,
+    // The track of the worker running the job by job id.
+    job_tracks: HashMap<usize, usize>,
+}
+
+impl TraceObserver {
+    pub fn new(file: &str) -> TraceObserver {
+        let mut trace_observer = TraceObserver {
+            file: file.to_owned(),
+            start: Instant::now(),
+            events: vec![],
+            tracks: HashSet::new(),
+            configure_start: None,
+            build_start: None,
+            job_tracks: HashMap::new(),
+        };
+        trace_observer.name_track(SCHEDULER_TRACK, "scheduler");
+        return trace_observer;
+    }
+
+    fn name_track(&mut self, track: usize, name: &str) {
+        self.tracks.insert(track);
+        self.events.push(json!({ "name": "thread_name", "ph": "M", "pid": 1, "tid": track, "args": { "name": name } }));
+    }
+
+    /// Adds a complete event, the times are in microseconds since the trace started.
+    fn add_span(&mut self, name: &str, category: &str, track: usize, start: Instant, duration: Duration, args: Value) {
+        self.events.push(json!({
+            "name": name,
+            "cat": category,
+            "ph": "X",
+            "pid": 1,
+            "tid": track,
+            "ts": start.duration_since(self.start).as_micros() as u64,
+            "dur": duration.as_micros() as u64,
+            "args": args,
+        }));
+    }
+
+    /// The whole trace is written after every build, so the file is complete while watching.
+    fn write(&self) {
+        let trace = json!({ "traceEvents": self.events, "displayTimeUnit": "ms" });
+        if let Err(e) = fs::write(&self.file, trace.to_string()) {
+            println!("ERROR: Could not write {}: {}", self.file, e);
+        }
+    }
+}
+
+impl BuildObserver for TraceObserver {
+    fn configure_started(&mut self) {
+        self.configure_start = Some(Instant::now());
+    }
+
+    fn configure_phase_finished(&mut self, phase: &str, duration: Duration) {
+        self.add_span(phase, "configure", SCHEDULER_TRACK, Instant::now() - duration, duration, json!({}));
+    }
+
+    fn configure_finished(&mut self, _result: Result<&DependencyGraph, &str>) {
+        if let Some(configure_start) = self.configure_start.take() {
+            self.add_span("configure", "configure", SCHEDULER_TRACK, configure_start, configure_start.elapsed(), json!({}));
+        }
+    }
+
+    fn build_started(&mut self, _graph: &DependencyGraph, _targets: &[Ref<DependencyNode>]) {
+        self.build_start = Some(Instant::now());
+    }
+
+    fn job_started(&mut self, _graph: &DependencyGraph, job: &Job, worker_id: usize) {
+        let track = worker_id + 1;
+        if !self.tracks.contains(&track) {
+            self.name_track(track, &format!("worker {}", worker_id));
+        }
+        self.job_tracks.insert(job.id, track);
+    }
+
+    fn job_finished(&mut self, graph: &DependencyGraph, job: &Job, result: &WorkResult) {
+        let Some(track) = self.job_tracks.remove(&job.id) else {
+            return;
+        };
+        let args = json!({
+            "target": graph.get_name(job.target),
+            "reason": job.reason,
+            "succeeded": result.result.is_ok(),
+        });
+        // The times of the worker, the scheduler only hears of the job some time later.
+        self.add_span(&job.description, "job", track, result.start, result.duration, args);
+    }
+
+    fn scheduler_idle(&mut self, _graph: &DependencyGraph, duration: Duration) {
+        self.add_span("idle", "scheduler", SCHEDULER_TRACK, Instant::now() - duration, duration, json!({}));
+    }
+
+    fn build_finished(&mut self, _graph: &DependencyGraph, succeeded: bool, _number_of_unbuilt_targets: usize) {
+        if let Some(build_start) = self.build_start.take() {
+            self.add_span("build", "build", SCHEDULER_TRACK, build_start, build_start.elapsed(), json!({ "succeeded": succeeded }));
+        }
+        self.write();
+    }
+}
@@ -0,0 +1,171 @@
+#![allow(clippy::needless_return)]
+use std::collections::{HashMap, HashSet};
+use std::path::{Path};
+use std::time::{Duration};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use crate::build_observer::{BuildObserver};
+use crate::configurator::{configure_clib_project, get_clib_source_dirs, is_project_file};
+use crate::dependency_graph::{DependencyGraph};
+use crate::scheduler::{Scheduler};
+
+/// The changes that were observed while waiting.
+#[derive(Default)]
+pub struct ChangeSet {
+    pub changed_files: Vec<String>,
+    // Set when a manifest or the contents of a directory changed, the graph has to be configured again.
+    pub needs_reconfigure: bool,
+}
+
+/// Watches the files of a dependency graph using inotify.
+/// Directories are watched instead of the files themselves, because editors often replace a file
+/// instead of writing to it.
+pub struct FileWatcher {
+    inotify: Inotify,
+    watched_dirs: HashMap<WatchDescriptor, String>,
+    watched_files: HashSet<String>,
+    // Adding or removing files in these directories changes the project structure.
+    listing_dirs: HashSet<String>,
+}
+
+impl FileWatcher {
+    pub fn new() -> FileWatcher {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).expect("Could not initialize inotify");
+
+        return FileWatcher {
+            inotify,
+            watched_dirs: HashMap::new(),
+            watched_files: HashSet::new(),
+            listing_dirs: HashSet::new(),
+        };
+    }
+
+    /// Replaces all watches by watches for the files referenced by the graph and the directories
+    /// the project was configured from.
+    pub fn watch_graph(&mut self, graph: &DependencyGraph, listing_dirs: Vec<String>) {
+        for watch_descriptor in self.watched_dirs.keys() {
+            // The watch is already gone when the directory was removed.
+            let _ = self.inotify.rm_watch(*watch_descriptor);
+        }
+        self.watched_dirs.clear();
+        self.watched_files.clear();
+        self.listing_dirs = listing_dirs.into_iter().collect();
+
+        let mut dirs: HashSet<String> = self.listing_dirs.clone();
+        for node in graph.get_nodes() {
+            for file in graph.get_files(node) {
+                if let Some(parent) = Path::new(&file).parent() {
+                    dirs.insert(parent.to_string_lossy().into_owned());
+                }
+                self.watched_files.insert(file);
+            }
+        }
+
+        let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_DELETE_SELF;
+        for dir in dirs {
+            match self.inotify.add_watch(dir.as_str(), flags) {
+                Ok(watch_descriptor) => {
+                    self.watched_dirs.insert(watch_descriptor, dir);
+                },
+                Err(e) => {
+                    println!("Could not watch directory {}: {}", dir, e);
+                },
+            }
+        }
+    }
+
+    /// Blocks until something changed, then keeps collecting changes until it has been quiet for
+    /// the debounce duration.
+    pub fn wait_for_changes(&mut self, debounce: Duration) -> ChangeSet {
+        let mut change_set = ChangeSet::default();
+        // Not every event is a change we are interested in, e.g. removing a watch also creates one.
+        while change_set.changed_files.is_empty() && !change_set.needs_reconfigure {
+            self.wait_for_events(-1);
+            self.read_events(&mut change_set);
+        }
+        while self.wait_for_events(debounce.as_millis() as i32) {
+            self.read_events(&mut change_set);
+        }
+
+        return change_set;
+    }
+
+    /// Returns the changes that happened since the last call, without blocking.
+    pub fn get_changes(&mut self) -> ChangeSet {
+        let mut change_set = ChangeSet::default();
+        while self.wait_for_events(0) {
+            self.read_events(&mut change_set);
+        }
+
+        return change_set;
+    }
+
+    /// Brings the graph and the build state up to date with the changes.
+    pub fn apply_changes(&mut self, change_set: ChangeSet, project_dir: &str, graph: &mut DependencyGraph, scheduler: &mut Scheduler, observer: &mut dyn BuildObserver) {
+        if change_set.needs_reconfigure {
+            println!("Project structure changed, reconfiguring");
+            observer.configure_started();
+            let configure_result = configure_clib_project(project_dir, observer);
+            observer.configure_finished(configure_result.as_ref().map_err(|e| e.as_str()));
+            match configure_result {
+                Ok(new_graph) => {
+                    *graph = new_graph;
+                    scheduler.reset();
+                },
+                // Keep building with the old graph until the project is fixed.
+                Err(e) => println!("Could not configure the project: {}", e),
+            }
+            self.watch_graph(graph, get_clib_source_dirs(project_dir));
+        }
+        for changed_file in change_set.changed_files.iter() {
+            println!("Changed: {}", changed_file);
+        }
+        scheduler.invalidate(graph, &change_set.changed_files);
+    }
+
+    /// Returns whether events are available before the timeout in milliseconds passed.
+    fn wait_for_events(&self, timeout: i32) -> bool {
+        let mut poll_fds = [PollFd::new(&self.inotify, PollFlags::POLLIN)];
+        return matches!(poll(&mut poll_fds, timeout), Ok(n) if n > 0);
+    }
+
+    fn read_events(&self, change_set: &mut ChangeSet) {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(_) => return,
+        };
+
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                // Events were lost, we no longer know what changed.
+                change_set.needs_reconfigure = true;
+                continue;
+            }
+            let Some(dir) = self.watched_dirs.get(&event.wd) else {
+                continue;
+            };
+            if event.mask.contains(AddWatchFlags::IN_DELETE_SELF) {
+                change_set.needs_reconfigure = true;
+                continue;
+            }
+            // Files with names that are not UTF-8 are never part of the project.
+            let Some(name) = event.name.as_ref().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let file = format!("{}/{}", dir, name);
+            let is_known_file = self.watched_files.contains(&file);
+            let is_listing_change = event.mask.intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO);
+
+            if file.ends_with("package.json") {
+                change_set.needs_reconfigure = true;
+            } else if is_known_file && !event.mask.intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM) {
+                if !change_set.changed_files.contains(&file) {
+                    change_set.changed_files.push(file);
+                }
+            } else if is_listing_change && self.listing_dirs.contains(dir) && (event.mask.contains(AddWatchFlags::IN_ISDIR) || is_project_file(&file)) {
+                change_set.needs_reconfigure = true;
+            }
+        }
+    }
+}
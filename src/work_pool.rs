@@ -1,32 +1,234 @@
+#![allow(clippy::needless_return)]
 use std::cmp;
-use std::process::{Command, ExitStatus};
+use std::collections::hash_map::{DefaultHasher};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::process::{CommandExt};
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use itertools::Itertools;
+use crate::filesystem::{normalize_path};
+use crate::jobserver::{Jobserver};
+use crate::throttle::{LINK_WEIGHT, ResourceLimits, Throttle};
+use crate::language::{Language};
 
 use threadpool::ThreadPool;
+use std::sync::{Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc::channel;
 
+static NEXT_WORKER_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Identifies the thread of the pool that executes a job.
+    static WORKER_ID: usize = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+}
+
 pub enum WorkInstruction {
     Link {
+        // Determines the driver, C++ objects have to be linked by the C++ driver.
+        language: Language,
         object_files: Vec<String>,
+        // Library search paths, passed as -L.
+        link_dirs: Vec<String>,
+        // Passed to the driver as is, e.g. "-Wl,--gc-sections".
+        link_flags: Vec<String>,
+        linker_script: Option<String>,
         link_libraries: Vec<String>,
         output_file: String
     },
+    // Combines the objects of a library into a static archive.
+    Archive {
+        object_files: Vec<String>,
+        output_file: String,
+    },
+    Custom {
+        command: Vec<String>,
+        working_dir: String,
+        outputs: Vec<String>,
+    },
+    RunTest {
+        executable: String,
+        working_dir: String,
+        // The test is killed when it runs longer.
+        timeout: Duration,
+    },
     Compile {
+        language: Language,
         source_file: String,
         include_dirs: Vec<String>,
+        defines: Vec<String>,
+        // Flags like optimization and warnings.
+        flags: Vec<String>,
         output_file: String,
     },
 }
 
+impl WorkInstruction {
+    pub fn get_output_file(&self) -> &str {
+        match self {
+            WorkInstruction::Link { output_file, .. } => output_file,
+            // The other outputs are written by the same command.
+            WorkInstruction::Archive { output_file, .. } => output_file,
+            WorkInstruction::Custom { outputs, .. } => &outputs[0],
+            // Running a test produces no file.
+            WorkInstruction::RunTest { executable, .. } => executable,
+            WorkInstruction::Compile { output_file, .. } => output_file,
+        }
+    }
+
+    /// The command that executes this instruction, the first element is the program.
+    pub fn get_command_line(&self) -> Vec<String> {
+        let mut command_line = vec![];
+        match self {
+            WorkInstruction::Link { language, object_files, link_dirs, link_flags, linker_script, link_libraries, output_file } => {
+                command_line.push(language.get_driver().to_owned());
+                command_line.extend(object_files.iter().cloned());
+                for link_dir in link_dirs {
+                    command_line.push(format!("-L{}", link_dir));
+                }
+                if let Some(linker_script) = linker_script {
+                    command_line.push("-T".to_owned());
+                    command_line.push(linker_script.clone());
+                }
+                command_line.extend(link_flags.iter().cloned());
+                // Libraries come after the objects, the linker only uses them for unresolved symbols.
+                for link_library in link_libraries {
+                    command_line.push(format!("-l{}", link_library));
+                }
+                command_line.push("-o".to_owned());
+                command_line.push(output_file.clone());
+            },
+            WorkInstruction::Archive { object_files, output_file } => {
+                command_line.push("/usr/bin/ar".to_owned());
+                // Insert the members into a new archive and write a symbol index.
+                command_line.push("rcs".to_owned());
+                command_line.push(output_file.clone());
+                command_line.extend(object_files.iter().cloned());
+            },
+            WorkInstruction::Custom { command, .. } => {
+                command_line.extend(command.iter().cloned());
+            },
+            WorkInstruction::RunTest { executable, .. } => {
+                command_line.push(executable.clone());
+            },
+            WorkInstruction::Compile { language, source_file, include_dirs, defines, flags, output_file } => {
+                command_line.push(language.get_driver().to_owned());
+                command_line.push(source_file.clone());
+
+                // We want to compile only
+                command_line.push("-c".to_owned());
+                if let Some(standard_flag) = language.get_standard_flag() {
+                    command_line.push(standard_flag.to_owned());
+                }
+
+                if language.uses_preprocessor() {
+                    // Let the compiler tell us which headers were used, so we know when to recompile.
+                    command_line.push("-MMD".to_owned());
+                    command_line.push("-MF".to_owned());
+                    command_line.push(get_depfile(output_file));
+
+                    for include_dir in include_dirs {
+                        command_line.push("-I".to_owned());
+                        command_line.push(include_dir.clone());
+                    }
+                    for define in defines {
+                        command_line.push(format!("-D{}", define));
+                    }
+                }
+                command_line.extend(flags.iter().cloned());
+
+                command_line.push("-o".to_owned());
+                command_line.push(output_file.clone());
+            },
+        }
+
+        return command_line;
+    }
+
+    /// How many slots of the work pool the instruction takes.
+    pub fn get_weight(&self) -> usize {
+        return match self {
+            WorkInstruction::Link { .. } => LINK_WEIGHT,
+            _ => 1,
+        };
+    }
+
+    /// The source a compile instruction translates, None for the other instructions.
+    pub fn get_source_file(&self) -> Option<&str> {
+        match self {
+            WorkInstruction::Compile { source_file, .. } => Some(source_file),
+            _ => None,
+        }
+    }
+
+    pub fn get_description(&self) -> String {
+        match self {
+            WorkInstruction::Link { output_file, .. } => format!("Linking {}", output_file),
+            WorkInstruction::Archive { output_file, .. } => format!("Archiving {}", output_file),
+            WorkInstruction::Custom { outputs, .. } => format!("Generating {}", outputs.join(", ")),
+            WorkInstruction::RunTest { executable, .. } => format!("Running {}", executable),
+            WorkInstruction::Compile { source_file, .. } => format!("Compiling {}", source_file),
+        }
+    }
+}
+
+/// Outputs are rebuilt when the hash of the command that produced them changes.
+pub fn hash_command_line(command_line: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    command_line.hash(&mut hasher);
+    return hasher.finish();
+}
+
+/// Objects mirror the directory structure of the sources in the build directory, so sources with
+/// the same name in different directories do not overwrite each other's objects.
+pub fn get_object_file(build_dir: &str, source_file: &str) -> String {
+    let source_file = normalize_path(source_file).trim_start_matches('/').replace("../", "__/");
+    return format!("{}/{}.o", build_dir, source_file);
+}
+
+/// The dependency file the compiler writes next to an object file.
+pub fn get_depfile(object_file: &str) -> String {
+    return format!("{}.d", object_file);
+}
+
 pub struct WorkResult {
-    job_id: usize,
-    result: Result<String, String>,
+    pub job_id: usize,
+    pub result: Result<String, String>,
+    // When the worker started the job, and how long it ran.
+    pub start: Instant,
+    pub duration: Duration,
+    // Only tests have a timeout.
+    pub timed_out: bool,
+    // None when the process could not be started.
+    pub process_status: Option<ProcessStatus>,
+}
+
+/// How the process of a job ended.
+#[derive(Debug, Copy, Clone)]
+pub struct ProcessStatus {
+    pub exit_status: i32,
+    // The maximum resident set size in kilobytes.
+    pub peak_rss: u64,
+    pub timed_out: bool,
+}
+
+/// Workers report when they start a job, and the result when they finish it.
+pub enum WorkEvent {
+    Started {
+        job_id: usize,
+        worker_id: usize,
+    },
+    Finished(WorkResult),
 }
 
 pub struct WorkPool {
     pool: ThreadPool,
-    channel_receiver: Receiver<WorkResult>,
+    channel_receiver: Receiver<WorkEvent>,
     worker: Worker,
     results: Vec<WorkResult>,
     next_job_id: usize,
@@ -34,7 +236,9 @@ pub struct WorkPool {
 }
 
 impl WorkPool {
-    pub fn new(num_workers: usize) -> WorkPool {
+    /// Jobs only start when there are enough free slots for their weight, every worker is a slot,
+    /// and the limits allow it. The jobserver can limit them further.
+    pub fn new(num_workers: usize, jobserver: Option<Jobserver>, limits: ResourceLimits) -> WorkPool {
         let (tx, rx) = channel();
 
         return WorkPool {
@@ -42,7 +246,12 @@ impl WorkPool {
             number_of_jobs_waiting: 0,
             next_job_id: 0,
             channel_receiver: rx,
-            worker: Worker { channel_sender: tx, job_id: 0 },
+            worker: Worker {
+                channel_sender: tx,
+                job_id: 0,
+                jobserver: jobserver.map(Arc::new),
+                throttle: Arc::new(Throttle::new(num_workers, limits)),
+            },
             results: vec![],
         };
     }
@@ -78,6 +287,22 @@ impl WorkPool {
         }
     }
 
+    /// Returns the next start or finish of a job, or None when no jobs are waiting.
+    pub fn get_next_event_blocking(&mut self) -> Option<WorkEvent> {
+        if let Some(result) = self.results.pop() {
+            return Some(WorkEvent::Finished(result));
+        }
+        if self.number_of_jobs_waiting == 0 {
+            return None;
+        }
+
+        let event = self.channel_receiver.recv().expect("Could not receive job result from channel");
+        if let WorkEvent::Finished(_) = event {
+            self.number_of_jobs_waiting -= 1;
+        }
+        return Some(event);
+    }
+
     pub fn get_next_result_blocking(&mut self) -> Option<WorkResult> {
         match self.results.pop() {
             Some(result) => { return Some(result) },
@@ -100,121 +325,263 @@ impl WorkPool {
         }
     }
 
-    /// Blocks waiting for the next result.
+    /// Blocks waiting for the next result, jobs that started are skipped.
     fn wait_for_result_from_channel(&mut self) -> WorkResult {
-        match self.channel_receiver.recv() {
-            Ok(result) => {
-                self.number_of_jobs_waiting -= 1;
-                return result;
+        loop {
+            match self.channel_receiver.recv() {
+                Ok(WorkEvent::Started { .. }) => {
+                    continue;
+                }
+                Ok(WorkEvent::Finished(result)) => {
+                    self.number_of_jobs_waiting -= 1;
+                    return result;
+                }
+                Err(_) => {panic!("Could not receive job result from channel"); }
             }
-            Err(_) => {panic!("Could not receive job result from channel"); }
         }
     }
 }
 
 #[derive(Clone)]
 struct Worker {
-    channel_sender: Sender<WorkResult>,
+    channel_sender: Sender<WorkEvent>,
     job_id: usize,
+    jobserver: Option<Arc<Jobserver>>,
+    throttle: Arc<Throttle>,
 }
 
 impl Worker {
     fn execute_work(self, instruction: WorkInstruction) {
-        let result = match instruction {
-            WorkInstruction::Link { object_files, link_libraries, output_file } => {
-                self.execute_linker(object_files, link_libraries, output_file)
+        let worker_id = WORKER_ID.with(|worker_id| *worker_id);
+        let admission = self.throttle.admit(instruction.get_weight(), instruction.get_output_file());
+        // A jobserver that is gone cannot hold the job back any longer.
+        let token = self.jobserver.as_ref().and_then(|jobserver| jobserver.acquire().ok());
+        let start = Instant::now();
+        // Only informative, a pool that is gone is noticed when sending the result.
+        let _ = self.channel_sender.send(WorkEvent::Started { job_id: self.job_id, worker_id });
+        let command_line = instruction.get_command_line();
+        let (result, process_status) = match instruction {
+            WorkInstruction::Link { .. } => {
+                self.execute_linker(command_line)
+            }
+            WorkInstruction::Archive { ref output_file, .. } => {
+                // Members of an existing archive would be kept.
+                let _ = fs::remove_file(output_file);
+                self.execute_linker(command_line)
             }
-            WorkInstruction::Compile { source_file, include_dirs, output_file } => {
-                self.execute_compiler(source_file, include_dirs, output_file)
+            WorkInstruction::Custom { ref working_dir, ref outputs, .. } => {
+                // Generators should not have to create the directories of their outputs.
+                for output in outputs {
+                    if let Some(output_dir) = Path::new(output).parent() {
+                        let _ = fs::create_dir_all(output_dir);
+                    }
+                }
+                self.execute_custom_command(command_line, working_dir)
+            }
+            WorkInstruction::RunTest { ref working_dir, timeout, .. } => {
+                self.execute_test(command_line, working_dir, timeout)
+            }
+            WorkInstruction::Compile { ref output_file, .. } => {
+                if let Some(output_dir) = Path::new(output_file).parent() {
+                    let _ = fs::create_dir_all(output_dir);
+                }
+                self.execute_compiler(command_line)
             }
         };
+        if let (Some(jobserver), Some(token)) = (&self.jobserver, token) {
+            jobserver.release(token);
+        }
+        self.throttle.release(admission);
 
-        match self.channel_sender.send(WorkResult {
+        match self.channel_sender.send(WorkEvent::Finished(WorkResult {
             job_id: self.job_id,
-            result
-        }) {
+            result,
+            start,
+            duration: start.elapsed(),
+            timed_out: process_status.is_some_and(|process_status| process_status.timed_out),
+            process_status,
+        })) {
             Ok(_) => {
             },
             Err(e) => panic!("Failed to send job result: {}", e),
         }
     }
 
-    fn execute_compiler(&self, source_file: String, include_dirs: Vec<String>, output_file: String) -> Result<String, String> {
-        let mut command_root = Command::new("/usr/bin/gcc");
+    fn execute_compiler(&self, command_line: Vec<String>) -> (Result<String, String>, Option<ProcessStatus>) {
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
 
-        let mut command = command_root.arg(source_file);
-
-        // We want to compile only
-        command = command.arg("-c");
+        match run_process(command, None) {
+            // Warnings are part of the output of a successful compile as well.
+            Ok((output, status)) if status.exit_status == 0 => {
+                return (Ok(output), Some(status));
+            },
+            Ok((output, status)) => {
+                return (Err(format!("Failed to compile, exit status: {}\n{}", status.exit_status, output)), Some(status));
+            },
+            Err(e) => {
+                return (Err(format!("Failed to compile: {}", e)), None);
+            }
+        }
+    }
 
-        for include_dir in include_dirs {
-            command = command.arg("-I");
-            command = command.arg(include_dir);
-         }
+    fn execute_custom_command(&self, command_line: Vec<String>, working_dir: &str) -> (Result<String, String>, Option<ProcessStatus>) {
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
+        command.current_dir(working_dir);
+        // Custom commands can run sub-makes.
+        if let Some(jobserver) = &self.jobserver {
+            jobserver.pass_to(&mut command);
+        }
 
-        command = command.arg("-o");
-        command = command.arg(output_file);
+        match run_process(command, None) {
+            Ok((output, status)) if status.exit_status == 0 => {
+                return (Ok(output), Some(status));
+            },
+            Ok((output, status)) => {
+                return (Err(format!("Command failed in {}, exit status: {}, error: {}", working_dir, status.exit_status, output)), Some(status));
+            },
+            Err(e) => {
+                return (Err(format!("Failed to run {}: {}", command_line[0], e)), None);
+            }
+        }
+    }
 
-        match command.output() {
-            Ok(output) => {
-                match output.status.code().unwrap() {
-                    0 => {
-                        let output_string = String::from_utf8(output.stdout.as_slice().to_vec()).expect("Invalid characters in output");
-                        return Ok(output_string);
-                    },
-                    a => {
-                        // Add extra debug information in case of a compile failure
-                        let args = command.get_args().into_iter().map(|a| a.to_str().unwrap() ).join(" ");
-                        println!("gcc {}", args);
+    /// Runs the test and captures its output, the test is killed when it exceeds the timeout.
+    fn execute_test(&self, command_line: Vec<String>, working_dir: &str, timeout: Duration) -> (Result<String, String>, Option<ProcessStatus>) {
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
+        command.current_dir(working_dir);
 
-                        let error_string = String::from_utf8(output.stderr.as_slice().to_vec()).expect("Invalid characters in output");
-                        return Err(format!("Failed to compile, exit status: {}, error: {}", a, error_string));
-                    }
-                }
+        match run_process(command, Some(timeout)) {
+            Ok((output, status)) if status.timed_out => {
+                return (Err(format!("Test timed out after {} s\n{}", timeout.as_secs_f32(), output)), Some(status));
+            },
+            Ok((output, status)) if status.exit_status == 0 => {
+                return (Ok(output), Some(status));
+            },
+            Ok((output, status)) => {
+                return (Err(format!("Test failed, exit status: {}\n{}", status.exit_status, output)), Some(status));
             },
             Err(e) => {
-                return Err(format!("Failed to compile: {}", e));
+                return (Err(format!("Failed to run {}: {}", command_line[0], e)), None);
             }
         }
     }
 
-    fn execute_linker(&self, object_files: Vec<String>, link_libraries: Vec<String>, output_file: String) -> Result<String, String> {
-        let mut command_root = Command::new("/usr/bin/gcc");
-
-        for object_file in object_files {
-            command_root.arg(object_file);
-         }
+    fn execute_linker(&self, command_line: Vec<String>) -> (Result<String, String>, Option<ProcessStatus>) {
+        let mut command = Command::new(&command_line[0]);
+        command.args(&command_line[1..]);
 
-        for link_library in link_libraries {
-            let link_flag = format!("-l{}", link_library);
-            command_root.arg(link_flag);
+        match run_process(command, None) {
+            Ok((output, status)) if status.exit_status == 0 => {
+                return (Ok(output), Some(status));
+            },
+            Ok((output, status)) => {
+                let error_truncated: String = output.chars().take(2000).collect();
+                return (Err(format!("Failed to link, exit status: {}, error: {}", status.exit_status, error_truncated)), Some(status));
+            },
+            Err(e) => {
+                return (Err(format!("Failed to link: {}", e)), None);
+            }
         }
+    }
+}
 
-        command_root.arg("-o");
-        command_root.arg(output_file);
-
-        match command_root.output() {
-            Ok(output) => {
-                match output.status.code().unwrap() {
-                    0 => {
-                        let output_string = String::from_utf8(output.stdout.as_slice().to_vec()).expect("Invalid characters in output");
-                        return Ok(output_string);
-                    },
-                    a => {
-                        // Add extra debug information in case of a linking failure
-                        let args = command_root.get_args().into_iter().map(|a| a.to_str().unwrap() ).join(" ");
-                        println!("gcc {}", args);
+/// Runs the command and returns everything it wrote, stdout followed by stderr. The process is
+/// reaped with wait4, which also tells how much memory it used. The process is killed when it
+/// runs longer than the timeout, together with the processes it started: a process with a timeout
+/// runs in its own process group.
+fn run_process(mut command: Command, timeout: Option<Duration>) -> io::Result<(String, ProcessStatus)> {
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    if timeout.is_some() {
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+    // Read the output while the process runs, so it does not block on a full pipe.
+    let stdout_reader = child.stdout.take().map(read_in_background);
+    let stderr_reader = child.stderr.take().map(read_in_background);
 
-                        let error_string = String::from_utf8(output.stderr.as_slice().to_vec()).expect("Invalid characters in output");
-                        let error_truncated: String = error_string.chars().take(2000).collect();
-                        return Err(format!("Failed to link, exit status: {}, error: {}", a, error_truncated));
-                    }
+    let pid = child.id() as libc::pid_t;
+    let start = Instant::now();
+    let mut timed_out = false;
+    let mut wait_status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // Without a timeout there is nothing to do but wait.
+        let options = if timeout.is_some() { libc::WNOHANG } else { 0 };
+        match unsafe { libc::wait4(pid, &mut wait_status, options, &mut usage) } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != ErrorKind::Interrupted {
+                    return Err(error);
                 }
             },
-            Err(e) => {
-                return Err(format!("Failed to compile: {}", e));
-            }
+            0 => {
+                if !timed_out && timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    unsafe { libc::killpg(pid, libc::SIGKILL) };
+                    timed_out = true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            },
+            _ => break,
         }
     }
+
+    // A killed process may have passed its pipes on to a process that left its group, the rest of
+    // the output is not worth waiting for.
+    let readers: Vec<_> = [stdout_reader, stderr_reader].into_iter().flatten().collect();
+    let readers_deadline = Instant::now() + Duration::from_secs(1);
+    while timed_out && Instant::now() < readers_deadline && !readers.iter().all(|reader| reader.is_finished()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut output = String::new();
+    for reader in readers {
+        if !timed_out || reader.is_finished() {
+            output.push_str(&reader.join().unwrap_or_default());
+        }
+    }
+    // Like shells do, a process killed by a signal gets 128 plus the signal as exit status.
+    let exit_status = if libc::WIFEXITED(wait_status) { libc::WEXITSTATUS(wait_status) } else { 128 + libc::WTERMSIG(wait_status) };
+
+    return Ok((output, ProcessStatus {
+        exit_status,
+        // Linux reports the maximum resident set size in kilobytes.
+        peak_rss: usage.ru_maxrss as u64,
+        timed_out,
+    }));
+}
+
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<String> {
+    return thread::spawn(move || {
+        let mut output = vec![];
+        let _ = reader.read_to_end(&mut output);
+        return String::from_utf8_lossy(&output).into_owned();
+    });
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_shell(script: &str, timeout: Duration) -> (ProcessStatus, Duration) {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        let start = Instant::now();
+        let (_, status) = run_process(command, Some(timeout)).unwrap();
+        return (status, start.elapsed());
+    }
+
+    #[test]
+    fn run_process_kills_the_processes_started_by_a_timed_out_process() {
+        let (status, duration) = run_shell("sleep 30 & sleep 30", Duration::from_millis(100));
+        assert!(status.timed_out);
+        assert!(duration < Duration::from_secs(5));
+
+        // A process in a session of its own keeps the pipes open.
+        let (status, duration) = run_shell("setsid sleep 5 & sleep 30", Duration::from_millis(100));
+        assert!(status.timed_out);
+        assert!(duration < Duration::from_secs(3));
+    }
+}